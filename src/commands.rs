@@ -1,277 +1,1352 @@
-use crate::resp::Value;
-use crate::db::{Db, DbValue};
-use bytes::Bytes;
-use tokio::sync::mpsc;
-use glob;
+use crate::resp::{format_double, Value};
+use crate::config::OutputBufferLimit;
+use crate::connection::ConnectionState;
+use crate::db::{unix_time_millis, DataType, Db, DbValue, PauseMode, PubSubMessage, Shard, Subscription};
+use bytes::{Bytes, BytesMut};
+use std::collections::HashMap;
+use std::future::Future;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::pin::Pin;
+use std::sync::LazyLock;
+use std::time::{Duration, Instant};
+use crate::glob::glob_match;
+
+pub const WRONGTYPE: &str = "WRONGTYPE Operation against a key holding the wrong kind of value";
+pub const NOT_INTEGER: &str = "ERR value is not an integer or out of range";
+pub const SYNTAX_ERROR: &str = "ERR syntax error";
+pub const INCR_OVERFLOW: &str = "ERR increment or decrement would overflow";
+
+/// Shared error replies so every handler emits byte-identical text
+pub fn wrongtype() -> Value {
+    Value::Error(WRONGTYPE.to_string())
+}
+
+pub fn not_integer() -> Value {
+    Value::Error(NOT_INTEGER.to_string())
+}
+
+pub fn syntax_error() -> Value {
+    Value::Error(SYNTAX_ERROR.to_string())
+}
+
+/// `name` is the lowercase command, or `command|subcommand` for subcommands
+pub fn wrong_arity(name: &str) -> Value {
+    Value::Error(format!("ERR wrong number of arguments for '{}' command", name))
+}
+
+/// Reply for a container command (CLIENT, ...) given a subcommand it doesn't have
+fn unknown_subcommand(command: &str, subcommand: &str) -> Value {
+    Value::Error(format!(
+        "ERR Unknown subcommand or wrong number of arguments for '{}'. Try {} HELP.",
+        subcommand, command
+    ))
+}
+
+/// Redis' unknown-command error, which echoes the command and the start of its arguments
+fn unknown_command(name: &[u8], args: &[Value]) -> Value {
+    let mut message = format!("ERR unknown command '{}', with args beginning with: ", String::from_utf8_lossy(name));
+    for arg in args {
+        if let Some(arg) = extract_string(arg) {
+            message.push_str(&format!("'{}' ", arg));
+        }
+    }
+    Value::Error(message)
+}
+
+/// The integer `b` spells in canonical decimal form, the only form Redis treats as
+/// an integer: no sign other than a leading `-`, no leading zeros, no `-0`, no
+/// whitespace, and within i64
+fn is_int_encodable(b: &[u8]) -> Option<i64> {
+    let digits = b.strip_prefix(b"-").unwrap_or(b);
+    match digits {
+        [b'0'] if digits.len() == b.len() => return Some(0),
+        [b'1'..=b'9', rest @ ..] if rest.iter().all(u8::is_ascii_digit) => {}
+        _ => return None,
+    }
+    std::str::from_utf8(b).ok()?.parse().ok()
+}
+
+/// Parses an integer argument, replying with the standard not-an-integer error
+fn parse_i64_arg(value: &Value) -> Result<i64, Value> {
+    extract_bytes(value)
+        .and_then(|b| is_int_encodable(&b))
+        .ok_or_else(not_integer)
+}
+
+/// Parses a count-like argument that can't be negative
+fn parse_usize_arg(value: &Value) -> Result<usize, Value> {
+    let n = parse_i64_arg(value)?;
+    usize::try_from(n).map_err(|_| Value::Error("ERR value is out of range, must be positive".to_string()))
+}
 
 #[derive(Debug, PartialEq)]
 pub enum CommandResult {
     Value(Value),
-    Subscribe(String),
+    Subscribe(Vec<Bytes>),
+    SSubscribe(Vec<Bytes>),
+    /// Write the optional final reply, then close the connection
+    Close(Option<Value>),
+}
+
+async fn handle_type(db: &Db, args: &[Value]) -> Option<Value> {
+    let key = extract_string(&args[0])?;
+    let mut shard = db.shard(&key).write().await;
+    let type_name = match shard.get_live(&key).map(|v| &v.data) {
+        Some(DataType::String(_)) => "string",
+        Some(DataType::List(_)) => "list",
+        None => "none",
+    };
+    Some(Value::SimpleString(type_name.to_string()))
+}
+
+/// Counts keys that haven't expired, whether or not they've been reaped yet
+async fn handle_dbsize(db: &Db, _args: &[Value]) -> Option<Value> {
+    let now = db.now();
+    let mut count = 0;
+    for shard in db.shards() {
+        count += shard.read().await.iter().filter(|(_, v)| !v.is_expired(now)).count();
+    }
+    Some(Value::Integer(count as i64))
+}
+
+/// Removes every key, keeping the maps' capacity; pub/sub channels are untouched
+async fn handle_flushdb(db: &Db, _args: &[Value]) -> Option<Value> {
+    for shard in db.lock_all_shards().await.iter_mut() {
+        shard.clear();
+    }
+    Some(Value::SimpleString("OK".to_string()))
 }
 
 async fn handle_keys(db: &Db, args: &[Value]) -> Option<Value> {
-    if args.len() != 1 {
-        return None;
+    let pattern = extract_bytes(&args[0])?;
+    let now = db.now();
+    let mut keys = Vec::new();
+    for shard in db.shards() {
+        keys.extend(shard.read().await.iter()
+            .filter(|(k, v)| !v.is_expired(now) && glob_match(&pattern, k.as_bytes()))
+            .map(|(k, _)| Value::BulkString(Bytes::from(k.clone()))));
     }
-    let pattern = extract_string(&args[0])?;
-    let db_lock = db.read().await;
-    let keys: Vec<Value> = db_lock.data.iter()
-        .filter(|(_, v)| !v.is_expired())
-        .filter_map(|(k, _)| {
-            if glob::Pattern::new(&pattern).ok()?.matches(k) {
-                Some(Value::BulkString(Bytes::from(k.clone())))
-            } else {
-                None
-            }
-        })
-        .collect();
     Some(Value::Array(keys))
 }
 
-pub async fn handle_command(db: &Db, cmd: &[Value]) -> Option<CommandResult> {
-    if cmd.is_empty() {
-        return None;
+fn scan_hash(key: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Most keys one SCAN call will visit, whatever COUNT asks for
+const SCAN_MAX_COUNT: usize = 1000;
+
+/// SCAN walks keys in order of a fixed hash and the cursor is the hash of the next
+/// key to visit. The order doesn't depend on what else is in the map, so a key that
+/// exists for the whole scan is always returned no matter what is inserted or deleted.
+async fn handle_scan(db: &Db, args: &[Value]) -> Option<Value> {
+    let cursor: u64 = match extract_string(&args[0])?.parse() {
+        Ok(cursor) => cursor,
+        Err(_) => return Some(Value::Error("ERR invalid cursor".to_string())),
+    };
+    let mut pattern = None;
+    let mut count = 10;
+    let mut i = 1;
+    while i < args.len() {
+        if i + 1 >= args.len() {
+            return Some(syntax_error());
+        }
+        match extract_string(&args[i])?.to_uppercase().as_str() {
+            "MATCH" => pattern = Some(extract_bytes(&args[i + 1])?),
+            "COUNT" => match parse_usize_arg(&args[i + 1]) {
+                Ok(0) => return Some(syntax_error()),
+                Ok(n) => count = n.min(SCAN_MAX_COUNT),
+                Err(e) => return Some(e),
+            },
+            _ => return Some(syntax_error()),
+        }
+        i += 2;
     }
 
-    match &cmd[0] {
-        Value::BulkString(bs) => {
-            let cmd_str = std::str::from_utf8(bs.as_ref()).ok()?;
-            match cmd_str.to_uppercase().as_str() {
-                "PING" => Some(CommandResult::Value(Value::SimpleString("PONG".to_string()))),
-                "SET" => handle_set(db, &cmd[1..]).await.map(CommandResult::Value),
-                "GET" => handle_get(db, &cmd[1..]).await.map(CommandResult::Value),
-                "DEL" => handle_del(db, &cmd[1..]).await.map(CommandResult::Value),
-                "SUBSCRIBE" => handle_subscribe(db, &cmd[1..]).await,
-                "PUBLISH" => handle_publish(db, &cmd[1..]).await.map(CommandResult::Value),
-                "INCR" => handle_incr(db, &cmd[1..]).await.map(CommandResult::Value),
-                "DECR" => handle_decr(db, &cmd[1..]).await.map(CommandResult::Value),
-                "EXISTS" => handle_exists(db, &cmd[1..]).await.map(CommandResult::Value),
-                "KEYS" => handle_keys(db, &cmd[1..]).await.map(CommandResult::Value),
-                _ => None,
-            }
+    // Expired keys are skipped rather than removed so SCAN can stay on read locks;
+    // lazy and active expiration will get to them
+    let now = db.now();
+    let mut candidates: Vec<(u64, String, bool)> = Vec::new();
+    for shard in db.shards() {
+        candidates.extend(shard.read().await.iter()
+            .map(|(k, v)| (scan_hash(k), k, v.is_expired(now)))
+            .filter(|(hash, _, _)| *hash >= cursor)
+            .map(|(hash, k, expired)| (hash, k.clone(), expired)));
+    }
+    candidates.sort();
+
+    let mut keys = Vec::new();
+    let mut next_cursor = 0;
+    for (idx, (hash, key, expired)) in candidates.iter().enumerate() {
+        // Never split keys sharing a hash across calls, the cursor couldn't tell them apart
+        if idx >= count && *hash != candidates[idx - 1].0 {
+            next_cursor = *hash;
+            break;
+        }
+        if !expired && pattern.as_ref().is_none_or(|p| glob_match(p, key.as_bytes())) {
+            keys.push(Value::BulkString(Bytes::from(key.clone())));
         }
-        _ => None,
     }
+    Some(Value::Array(vec![
+        Value::BulkString(Bytes::from(next_cursor.to_string())),
+        Value::Array(keys),
+    ]))
 }
 
-async fn handle_set(db: &Db, args: &[Value]) -> Option<Value> {
-    if args.len() < 2 {
+type HandlerFuture<'a> = Pin<Box<dyn Future<Output = Option<CommandResult>> + Send + 'a>>;
+type Handler = for<'a> fn(&'a Db, &'a mut ConnectionState, &'a [Value]) -> HandlerFuture<'a>;
+
+/// Adapts an `async fn(&Db, &[Value])` into a table `Handler`. Plain handlers reply
+/// with a `Value`; `raw` ones already produce a `CommandResult`, and `state` ones
+/// also take the connection's `ConnectionState`.
+macro_rules! handler {
+    ($f:ident) => {
+        |db, _state, args| Box::pin(async move { $f(db, args).await.map(CommandResult::Value) })
+    };
+    ($f:ident, raw) => {
+        |db, _state, args| Box::pin($f(db, args))
+    };
+    ($f:ident, state) => {
+        |db, state, args| Box::pin(async move { $f(db, state, args).await.map(CommandResult::Value) })
+    };
+}
+
+/// Everything the server knows about a command, in the shape of Redis' command table
+pub struct CommandSpec {
+    pub name: &'static str,
+    handler: Handler,
+    /// N means exactly N words including the command name, -N means at least N
+    pub arity: i32,
+    pub flags: &'static [&'static str],
+    /// Position of the first and last key (negative counts from the end) and the
+    /// step between keys; all 0 when the command has no fixed key positions
+    pub first_key: i32,
+    pub last_key: i32,
+    pub step: i32,
+}
+
+impl CommandSpec {
+    const fn new(
+        name: &'static str,
+        handler: Handler,
+        arity: i32,
+        flags: &'static [&'static str],
+        (first_key, last_key, step): (i32, i32, i32),
+    ) -> Self {
+        Self { name, handler, arity, flags, first_key, last_key, step }
+    }
+
+    pub fn arity_matches(&self, argc: usize) -> bool {
+        let argc = argc as i32;
+        if self.arity >= 0 { argc == self.arity } else { argc >= -self.arity }
+    }
+
+    pub fn is_write(&self) -> bool {
+        self.flags.contains(&"write")
+    }
+}
+
+const NO_KEYS: (i32, i32, i32) = (0, 0, 0);
+const ONE_KEY: (i32, i32, i32) = (1, 1, 1);
+const ALL_KEYS: (i32, i32, i32) = (1, -1, 1);
+
+static COMMAND_TABLE: LazyLock<HashMap<&'static str, CommandSpec>> = LazyLock::new(|| {
+    [
+        CommandSpec::new("PING", handler!(handle_ping), -1, &["fast"], NO_KEYS),
+        CommandSpec::new("QUIT", handler!(handle_quit, raw), -1, &["fast"], NO_KEYS),
+        CommandSpec::new("RESET", handler!(handle_reset, state), 1, &["fast"], NO_KEYS),
+        CommandSpec::new("SET", handler!(handle_set), -3, &["write"], ONE_KEY),
+        CommandSpec::new("SETNX", handler!(handle_setnx), 3, &["write", "fast"], ONE_KEY),
+        CommandSpec::new("GET", handler!(handle_get), 2, &["readonly", "fast"], ONE_KEY),
+        CommandSpec::new("GETSET", handler!(handle_getset), 3, &["write", "fast"], ONE_KEY),
+        CommandSpec::new("GETDEL", handler!(handle_getdel), 2, &["write", "fast"], ONE_KEY),
+        CommandSpec::new("APPEND", handler!(handle_append), 3, &["write"], ONE_KEY),
+        CommandSpec::new("STRLEN", handler!(handle_strlen), 2, &["readonly", "fast"], ONE_KEY),
+        CommandSpec::new("DEL", handler!(handle_del), 2, &["write"], ONE_KEY),
+        CommandSpec::new("MSET", handler!(handle_mset), -3, &["write"], (1, -1, 2)),
+        CommandSpec::new("MGET", handler!(handle_mget), -2, &["readonly", "fast"], ALL_KEYS),
+        CommandSpec::new("SUBSCRIBE", handler!(handle_subscribe, raw), -2, &["pubsub"], NO_KEYS),
+        CommandSpec::new("PUBLISH", handler!(handle_publish), 3, &["pubsub", "fast"], NO_KEYS),
+        CommandSpec::new("SSUBSCRIBE", handler!(handle_ssubscribe, raw), -2, &["pubsub"], NO_KEYS),
+        CommandSpec::new("SPUBLISH", handler!(handle_spublish), 3, &["pubsub", "fast"], NO_KEYS),
+        CommandSpec::new("INCR", handler!(handle_incr), 2, &["write", "fast"], ONE_KEY),
+        CommandSpec::new("DECR", handler!(handle_decr), 2, &["write", "fast"], ONE_KEY),
+        CommandSpec::new("INCRBY", handler!(handle_incrby), 3, &["write", "fast"], ONE_KEY),
+        CommandSpec::new("DECRBY", handler!(handle_decrby), 3, &["write", "fast"], ONE_KEY),
+        CommandSpec::new("EXPIRE", handler!(handle_expire), 3, &["write", "fast"], ONE_KEY),
+        CommandSpec::new("EXPIREAT", handler!(handle_expireat), 3, &["write", "fast"], ONE_KEY),
+        CommandSpec::new("PEXPIREAT", handler!(handle_pexpireat), 3, &["write", "fast"], ONE_KEY),
+        CommandSpec::new("PERSIST", handler!(handle_persist), 2, &["write", "fast"], ONE_KEY),
+        CommandSpec::new("TTL", handler!(handle_ttl), 2, &["readonly", "fast"], ONE_KEY),
+        CommandSpec::new("EXISTS", handler!(handle_exists), -2, &["readonly", "fast"], ALL_KEYS),
+        CommandSpec::new("TYPE", handler!(handle_type), 2, &["readonly", "fast"], ONE_KEY),
+        CommandSpec::new("DBSIZE", handler!(handle_dbsize), 1, &["readonly", "fast"], NO_KEYS),
+        CommandSpec::new("FLUSHDB", handler!(handle_flushdb), 1, &["write"], NO_KEYS),
+        CommandSpec::new("KEYS", handler!(handle_keys), 2, &["readonly"], NO_KEYS),
+        CommandSpec::new("SCAN", handler!(handle_scan), -2, &["readonly"], NO_KEYS),
+        CommandSpec::new("LPUSH", handler!(handle_lpush), -3, &["write", "fast"], ONE_KEY),
+        CommandSpec::new("RPUSH", handler!(handle_rpush), -3, &["write", "fast"], ONE_KEY),
+        CommandSpec::new("LLEN", handler!(handle_llen), 2, &["readonly", "fast"], ONE_KEY),
+        CommandSpec::new("LINDEX", handler!(handle_lindex), 3, &["readonly"], ONE_KEY),
+        CommandSpec::new("LPOP", handler!(handle_lpop), -2, &["write", "fast"], ONE_KEY),
+        CommandSpec::new("RPOP", handler!(handle_rpop), -2, &["write", "fast"], ONE_KEY),
+        CommandSpec::new("LMPOP", handler!(handle_lmpop), -4, &["write", "movablekeys"], NO_KEYS),
+        CommandSpec::new("CLIENT", handler!(handle_client, state), -2, &["admin"], NO_KEYS),
+        CommandSpec::new("INFO", handler!(handle_info), -1, &[], NO_KEYS),
+        CommandSpec::new("LCS", handler!(handle_lcs), -3, &["readonly"], (1, 2, 1)),
+    ]
+    .into_iter()
+    .map(|spec| (spec.name, spec))
+    .collect()
+});
+
+/// Longer than any registered command name, so longer input can't match anything
+const MAX_COMMAND_NAME: usize = 32;
+
+pub fn lookup_command(name: &str) -> Option<&'static CommandSpec> {
+    COMMAND_TABLE.get(name)
+}
+
+/// Case-insensitive lookup of a raw command name. Uppercases into a stack buffer
+/// so the per-command hot path doesn't allocate.
+fn lookup_command_bytes(name: &[u8]) -> Option<&'static CommandSpec> {
+    if name.len() > MAX_COMMAND_NAME {
+        return None;
+    }
+    let mut buf = [0u8; MAX_COMMAND_NAME];
+    let upper = &mut buf[..name.len()];
+    upper.copy_from_slice(name);
+    upper.make_ascii_uppercase();
+    lookup_command(std::str::from_utf8(upper).ok()?)
+}
+
+pub async fn handle_command(db: &Db, state: &mut ConnectionState, cmd: &[Value]) -> Option<CommandResult> {
+    // Like Redis, an empty command (`*0\r\n` or a blank line) is ignored without a reply
+    if cmd.is_empty() {
         return None;
     }
+
+    let spec = match &cmd[0] {
+        Value::BulkString(name) => match lookup_command_bytes(name) {
+            Some(spec) => spec,
+            None => return Some(CommandResult::Value(unknown_command(name, &cmd[1..]))),
+        },
+        _ => return None,
+    };
+    if !spec.arity_matches(cmd.len()) {
+        return Some(CommandResult::Value(wrong_arity(&spec.name.to_lowercase())));
+    }
+    (spec.handler)(db, state, &cmd[1..]).await
+}
+
+async fn handle_ping(_db: &Db, args: &[Value]) -> Option<Value> {
+    match args {
+        [] => Some(Value::SimpleString("PONG".to_string())),
+        [message] => Some(Value::BulkString(extract_bytes(message)?)),
+        _ => Some(wrong_arity("ping")),
+    }
+}
+
+async fn handle_quit(_db: &Db, _args: &[Value]) -> Option<CommandResult> {
+    Some(CommandResult::Close(Some(Value::SimpleString("OK".to_string()))))
+}
+
+/// Drops the connection's name, protocol and DB choice. Leaving subscribed state is
+/// up to the subscribe loop, which sees RESET first.
+async fn handle_reset(_db: &Db, state: &mut ConnectionState, _args: &[Value]) -> Option<Value> {
+    state.reset();
+    Some(Value::SimpleString("RESET".to_string()))
+}
+
+async fn handle_set(db: &Db, args: &[Value]) -> Option<Value> {
     let key = extract_string(&args[0])?;
     let value = extract_bytes(&args[1])?;
-    let mut expiry = None;
-    if args.len() >= 4 && matches!(&args[2], Value::BulkString(bs) if bs.as_ref() == b"EX") {
-        if let Value::BulkString(ex_str) = &args[3] {
-            if let Ok(ex_secs) = std::str::from_utf8(ex_str.as_ref()).ok()?.parse::<u64>() {
-                expiry = Some(std::time::Instant::now() + std::time::Duration::from_secs(ex_secs));
+    let invalid_expire = || Value::Error("ERR invalid expire time in 'set' command".to_string());
+    let mut ttl = None;
+    // NX: only set a missing key, XX: only set an existing one
+    let (mut nx, mut xx) = (false, false);
+    let mut i = 2;
+    while i < args.len() {
+        let option = extract_string(&args[i])?.to_uppercase();
+        match option.as_str() {
+            // Only one of EX and PX may be given
+            "EX" | "PX" if ttl.is_none() && i + 1 < args.len() => {
+                i += 1;
+                let n = match parse_i64_arg(&args[i]) {
+                    Ok(n) if n > 0 => n as u64,
+                    Ok(_) => return Some(invalid_expire()),
+                    Err(e) => return Some(e),
+                };
+                ttl = Some(if option == "EX" { Duration::from_secs(n) } else { Duration::from_millis(n) });
             }
+            "NX" if !xx => nx = true,
+            "XX" if !nx => xx = true,
+            _ => return Some(syntax_error()),
         }
+        i += 1;
     }
     let mut db_val = DbValue::new_string(value);
-    db_val.expiry = expiry;
     {
-        let mut db_lock = db.write().await;
-        db_lock.data.insert(key, db_val);
+        let mut shard = db.shard(&key).write().await;
+        let existing_is_string = shard.get_live(&key).map(|v| matches!(v.data, DataType::String(_)));
+        if (nx || xx) && existing_is_string.is_some() == nx {
+            return Some(Value::Null);
+        }
+        if db.set_strict_type && existing_is_string == Some(false) {
+            return Some(wrongtype());
+        }
+        if let Some(ttl) = ttl {
+            match shard.now().checked_add(ttl) {
+                Some(expiry) => db_val.expiry = Some(expiry),
+                None => return Some(invalid_expire()),
+            }
+        }
+        shard.insert(key, db_val);
     }
     Some(Value::SimpleString("OK".to_string()))
 }
 
+/// `SET key value NX`, answering 1 or 0 instead of OK or Null
+async fn handle_setnx(db: &Db, args: &[Value]) -> Option<Value> {
+    let set_args = [args[0].clone(), args[1].clone(), Value::BulkString(Bytes::from("NX"))];
+    match handle_set(db, &set_args).await? {
+        Value::SimpleString(_) => Some(Value::Integer(1)),
+        Value::Null => Some(Value::Integer(0)),
+        other => Some(other),
+    }
+}
+
 async fn handle_get(db: &Db, args: &[Value]) -> Option<Value> {
-    if args.len() != 1 {
-        return None;
+    let key = extract_string(&args[0])?;
+    let mut shard = db.shard(&key).write().await; // Need write to remove if expired
+    match shard.get_live(&key).map(|v| &v.data) {
+        Some(DataType::String(bs)) => Some(Value::BulkString(bs.clone())),
+        Some(DataType::List(_)) => Some(wrongtype()),
+        None => Some(Value::Null),
     }
+}
+
+/// Replaces the value, dropping any TTL, and replies with the old one
+async fn handle_getset(db: &Db, args: &[Value]) -> Option<Value> {
     let key = extract_string(&args[0])?;
-    let mut db_lock = db.write().await; // Need write to remove if expired
-    if let Some(db_val) = db_lock.data.get(&key) {
-        if db_val.is_expired() {
-            db_lock.data.remove(&key);
-            return Some(Value::Null);
+    let value = extract_bytes(&args[1])?;
+    let mut shard = db.shard(&key).write().await;
+    let old = match shard.get_live(&key).map(|v| &v.data) {
+        Some(DataType::String(bs)) => Value::BulkString(bs.clone()),
+        Some(_) => return Some(wrongtype()),
+        None => Value::Null,
+    };
+    shard.insert(key, DbValue::new_string(value));
+    Some(old)
+}
+
+/// Removes a string key and replies with its value; other types are left alone
+async fn handle_getdel(db: &Db, args: &[Value]) -> Option<Value> {
+    let key = extract_string(&args[0])?;
+    let mut shard = db.shard(&key).write().await;
+    match shard.get_live(&key).map(|v| &v.data) {
+        Some(DataType::String(_)) => {}
+        Some(_) => return Some(wrongtype()),
+        None => return Some(Value::Null),
+    }
+    match shard.remove(&key).map(|v| v.data) {
+        Some(DataType::String(bs)) => Some(Value::BulkString(bs)),
+        _ => Some(Value::Null),
+    }
+}
+
+async fn handle_append(db: &Db, args: &[Value]) -> Option<Value> {
+    let key = extract_string(&args[0])?;
+    let suffix = extract_bytes(&args[1])?;
+    let mut shard = db.shard(&key).write().await;
+    let value = match shard.get_live_mut(&key).map(|v| &mut v.data) {
+        Some(DataType::String(bs)) => bs,
+        Some(_) => return Some(wrongtype()),
+        None => {
+            let len = suffix.len();
+            shard.insert(key, DbValue::new_string(suffix));
+            return Some(Value::Integer(len as i64));
         }
-        Some(Value::BulkString(db_val.data.clone()))
-    } else {
-        Some(Value::Null)
+    };
+    let mut joined = BytesMut::with_capacity(value.len() + suffix.len());
+    joined.extend_from_slice(value);
+    joined.extend_from_slice(&suffix);
+    *value = joined.freeze();
+    Some(Value::Integer(value.len() as i64))
+}
+
+async fn handle_strlen(db: &Db, args: &[Value]) -> Option<Value> {
+    let key = extract_string(&args[0])?;
+    let mut shard = db.shard(&key).write().await;
+    match shard.get_live(&key).map(|v| &v.data) {
+        Some(DataType::String(bs)) => Some(Value::Integer(bs.len() as i64)),
+        Some(_) => Some(wrongtype()),
+        None => Some(Value::Integer(0)),
     }
 }
 
 async fn handle_del(db: &Db, args: &[Value]) -> Option<Value> {
-    if args.len() != 1 {
-        return None;
-    }
     let key = extract_string(&args[0])?;
-    let mut db_lock = db.write().await;
-    let count = if db_lock.data.remove(&key).is_some() { 1 } else { 0 };
+    let mut shard = db.shard(&key).write().await;
+    // A key that has expired but not been reaped yet is already gone
+    let count = if shard.get_live(&key).is_some() {
+        shard.remove(&key);
+        1
+    } else {
+        0
+    };
     Some(Value::Integer(count))
 }
 
+/// Sets every key/value pair with all their shards locked, so no reader sees half of them
+async fn handle_mset(db: &Db, args: &[Value]) -> Option<Value> {
+    if !args.len().is_multiple_of(2) {
+        return Some(wrong_arity("mset"));
+    }
+    let pairs = args
+        .chunks(2)
+        .map(|pair| Some((extract_string(&pair[0])?, extract_bytes(&pair[1])?)))
+        .collect::<Option<Vec<_>>>()?;
+    let mut shards = db.lock_shards(pairs.iter().map(|(key, _)| key.as_str())).await;
+    for (key, value) in pairs {
+        shards.get_mut(&key).insert(key, DbValue::new_string(value));
+    }
+    Some(Value::SimpleString("OK".to_string()))
+}
+
+/// Missing keys and keys holding other types both come back as Null
+async fn handle_mget(db: &Db, args: &[Value]) -> Option<Value> {
+    let keys = args.iter().map(extract_string).collect::<Option<Vec<_>>>()?;
+    let mut shards = db.lock_shards(keys.iter().map(String::as_str)).await;
+    let now = db.now();
+    let values = keys
+        .iter()
+        .map(|key| match shards.get_mut(key).get(key) {
+            Some(v) if !v.is_expired(now) => match &v.data {
+                DataType::String(bs) => Value::BulkString(bs.clone()),
+                _ => Value::Null,
+            },
+            _ => Value::Null,
+        })
+        .collect();
+    Some(Value::Array(values))
+}
+
 fn extract_string(value: &Value) -> Option<String> {
     match value {
         Value::BulkString(bs) => std::str::from_utf8(bs.as_ref()).ok().map(|s| s.to_string()),
-        _ => None,
+        _ => coerce_resp3_arg(value),
     }
 }
 
 fn extract_bytes(value: &Value) -> Option<Bytes> {
     match value {
         Value::BulkString(bs) => Some(bs.clone()),
-        _ => None,
+        _ => coerce_resp3_arg(value).map(Bytes::from),
     }
 }
 
-async fn handle_subscribe(db: &Db, args: &[Value]) -> Option<CommandResult> {
-    if args.len() != 1 {
-        return None;
+/// RESP3 doubles and big numbers sent as arguments are treated as if the client
+/// had sent their decimal text as a bulk string
+fn coerce_resp3_arg(value: &Value) -> Option<String> {
+    match value {
+        Value::Double(d) => Some(format_double(*d)),
+        Value::BigNumber(n) => Some(n.clone()),
+        _ => None,
     }
-    let channel = extract_string(&args[0])?;
-    Some(CommandResult::Subscribe(channel))
+}
+
+async fn handle_subscribe(_db: &Db, args: &[Value]) -> Option<CommandResult> {
+    let channels = args.iter().map(extract_bytes).collect::<Option<Vec<_>>>()?;
+    Some(CommandResult::Subscribe(channels))
 }
 
 async fn handle_publish(db: &Db, args: &[Value]) -> Option<Value> {
-    if args.len() != 2 {
-        return None;
-    }
-    let channel = extract_string(&args[0])?;
+    let channel = extract_bytes(&args[0])?;
     let message = extract_bytes(&args[1])?;
-    let mut db_lock = db.write().await;
-    let count = if let Some(senders) = db_lock.channels.get_mut(&channel) {
-        let initial_count = senders.len();
-        senders.retain(|sender| {
-            // Try to send, remove if failed
-            sender.try_send(message.clone()).is_ok()
-        });
-        initial_count
-    } else {
-        0
-    };
+    let mut channels = db.channels.write().await;
+    let count = publish_to(&mut channels, &channel, message, false, &db.pubsub_output_limit, db.now());
     Some(Value::Integer(count as i64))
 }
 
-async fn handle_incr(db: &Db, args: &[Value]) -> Option<Value> {
-    if args.len() != 1 {
-        return None;
+async fn handle_ssubscribe(_db: &Db, args: &[Value]) -> Option<CommandResult> {
+    let channels = args.iter().map(extract_bytes).collect::<Option<Vec<_>>>()?;
+    Some(CommandResult::SSubscribe(channels))
+}
+
+async fn handle_spublish(db: &Db, args: &[Value]) -> Option<Value> {
+    let channel = extract_bytes(&args[0])?;
+    let message = extract_bytes(&args[1])?;
+    let mut channels = db.shard_channels.write().await;
+    let count = publish_to(&mut channels, &channel, message, true, &db.pubsub_output_limit, db.now());
+    Some(Value::Integer(count as i64))
+}
+
+/// Queues the message for every subscriber of `channel` and returns how many got it.
+/// Subscribers that have gone away, or that would go over their output buffer
+/// `limit`, are dropped from the channel.
+fn publish_to(
+    channels: &mut HashMap<Bytes, Vec<Subscription>>,
+    channel: &Bytes,
+    payload: Bytes,
+    sharded: bool,
+    limit: &OutputBufferLimit,
+    now: Instant,
+) -> usize {
+    let Some(subscriptions) = channels.get_mut(channel) else {
+        return 0;
+    };
+    let message = PubSubMessage { sharded, channel: channel.clone(), payload };
+    subscriptions.retain(|sub| {
+        sub.buffer.reserve(message.size(), limit, now) && sub.tx.send(message.clone()).is_ok()
+    });
+    let count = subscriptions.len();
+    if subscriptions.is_empty() {
+        channels.remove(channel);
     }
+    count
+}
+
+async fn handle_incr(db: &Db, args: &[Value]) -> Option<Value> {
     let key = extract_string(&args[0])?;
-    let mut db_lock = db.write().await;
-    if let Some(db_val) = db_lock.data.get_mut(&key) {
-        if db_val.is_expired() {
-            db_lock.data.remove(&key);
-            return Some(Value::Integer(1)); // New value
-        }
-        let current_str = std::str::from_utf8(db_val.data.as_ref()).ok()?;
-        let current: i64 = current_str.parse().ok()?;
-        let new_val = current + 1;
-        db_val.data = Bytes::from(new_val.to_string());
-        Some(Value::Integer(new_val))
-    } else {
-        let new_val = 1;
-        db_lock.data.insert(key, DbValue::new(Bytes::from(new_val.to_string())));
-        Some(Value::Integer(new_val))
-    }
+    Some(incr_by(db, key, 1).await)
 }
 
 async fn handle_decr(db: &Db, args: &[Value]) -> Option<Value> {
-    if args.len() != 1 {
-        return None;
+    let key = extract_string(&args[0])?;
+    Some(incr_by(db, key, -1).await)
+}
+
+async fn handle_incrby(db: &Db, args: &[Value]) -> Option<Value> {
+    let key = extract_string(&args[0])?;
+    match parse_i64_arg(&args[1]) {
+        Ok(delta) => Some(incr_by(db, key, delta).await),
+        Err(e) => Some(e),
     }
+}
+
+async fn handle_decrby(db: &Db, args: &[Value]) -> Option<Value> {
     let key = extract_string(&args[0])?;
-    let mut db_lock = db.write().await;
-    if let Some(db_val) = db_lock.data.get_mut(&key) {
-        if db_val.is_expired() {
-            db_lock.data.remove(&key);
-            return Some(Value::Integer(-1)); // New value
-        }
-        let current_str = std::str::from_utf8(db_val.data.as_ref()).ok()?;
-        let current: i64 = current_str.parse().ok()?;
-        let new_val = current - 1;
-        db_val.data = Bytes::from(new_val.to_string());
-        Some(Value::Integer(new_val))
-    } else {
-        let new_val = -1;
-        db_lock.data.insert(key, DbValue::new(Bytes::from(new_val.to_string())));
-        Some(Value::Integer(new_val))
+    match parse_i64_arg(&args[1]) {
+        Ok(delta) => match delta.checked_neg() {
+            Some(delta) => Some(incr_by(db, key, delta).await),
+            None => Some(Value::Error("ERR decrement would overflow".to_string())),
+        },
+        Err(e) => Some(e),
     }
 }
 
-async fn handle_exists(db: &Db, args: &[Value]) -> Option<Value> {
-    if args.len() != 1 {
-        return None;
+/// Adds `delta` to the integer stored at `key` (0 if missing) and replies with the result
+async fn incr_by(db: &Db, key: String, delta: i64) -> Value {
+    let mut shard = db.shard(&key).write().await;
+    let current = match shard.get_live(&key).map(|v| &v.data) {
+        Some(DataType::String(bs)) => match is_int_encodable(bs) {
+            Some(n) => n,
+            None => return not_integer(),
+        },
+        Some(_) => return wrongtype(),
+        None => 0,
+    };
+    let Some(new_val) = current.checked_add(delta) else {
+        return Value::Error(INCR_OVERFLOW.to_string());
+    };
+    // Keep any TTL the key already has
+    let value = Bytes::from(new_val.to_string());
+    match shard.get_live_mut(&key) {
+        Some(db_val) => db_val.data = DataType::String(value),
+        None => {
+            shard.insert(key, DbValue::new_string(value));
+        }
     }
+    Value::Integer(new_val)
+}
+
+async fn handle_expire(db: &Db, args: &[Value]) -> Option<Value> {
     let key = extract_string(&args[0])?;
-    let db_lock = db.read().await;
-    let exists = db_lock.data.get(&key).map_or(false, |v| !v.is_expired());
-    Some(Value::Integer(if exists { 1 } else { 0 }))
+    let secs = match parse_i64_arg(&args[1]) {
+        Ok(secs) => secs,
+        Err(e) => return Some(e),
+    };
+    let mut shard = db.shard(&key).write().await;
+    let now = shard.now();
+    if shard.get(&key).is_none_or(|v| v.is_expired(now)) {
+        shard.remove(&key);
+        return Some(Value::Integer(0));
+    }
+    // A TTL that has already run out deletes the key straight away
+    if secs <= 0 {
+        shard.remove(&key);
+        return Some(Value::Integer(1));
+    }
+    let Some(expiry) = now.checked_add(Duration::from_secs(secs as u64)) else {
+        return Some(Value::Error("ERR invalid expire time in 'expire' command".to_string()));
+    };
+    shard.set_expiry(&key, expiry);
+    Some(Value::Integer(1))
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::db::new_db;
+/// Longest expiry EXPIREAT/PEXPIREAT fall back to when the requested time is further
+/// out than an `Instant` can reach
+const MAX_EXPIRE: Duration = Duration::from_secs(100 * 365 * 24 * 60 * 60);
 
-    #[tokio::test]
-    async fn test_set_get() {
-        let db = new_db();
-        let cmd = vec![
-            Value::BulkString(Bytes::from("SET")),
-            Value::BulkString(Bytes::from("key")),
-            Value::BulkString(Bytes::from("value")),
-        ];
-        let resp = handle_command(&db, &cmd).await;
-        assert_eq!(resp, Some(CommandResult::Value(Value::SimpleString("OK".to_string()))));
+async fn handle_expireat(db: &Db, args: &[Value]) -> Option<Value> {
+    expire_at(db, args, 1000, "expireat").await
+}
 
-        let cmd_get = vec![
-            Value::BulkString(Bytes::from("GET")),
-            Value::BulkString(Bytes::from("key")),
-        ];
-        let resp_get = handle_command(&db, &cmd_get).await;
-        assert_eq!(resp_get, Some(CommandResult::Value(Value::BulkString(Bytes::from("value")))));
+async fn handle_pexpireat(db: &Db, args: &[Value]) -> Option<Value> {
+    expire_at(db, args, 1, "pexpireat").await
+}
+
+/// EXPIREAT/PEXPIREAT: `args[1]` is a Unix time counted in `unit_ms` milliseconds.
+/// A time that has passed deletes the key, and one further out than `MAX_EXPIRE` is
+/// clamped to it. Only a clock that can't reach even that is an error.
+async fn expire_at(db: &Db, args: &[Value], unit_ms: i128, name: &str) -> Option<Value> {
+    let key = extract_string(&args[0])?;
+    let at_ms = match parse_i64_arg(&args[1]) {
+        Ok(at) => at as i128 * unit_ms,
+        Err(e) => return Some(e),
+    };
+    let ttl_ms = at_ms - unix_time_millis() as i128;
+    let mut shard = db.shard(&key).write().await;
+    let now = shard.now();
+    if shard.get(&key).is_none_or(|v| v.is_expired(now)) {
+        shard.remove(&key);
+        return Some(Value::Integer(0));
+    }
+    if ttl_ms <= 0 {
+        shard.remove(&key);
+        return Some(Value::Integer(1));
     }
+    let ttl = Duration::from_millis(u64::try_from(ttl_ms).unwrap_or(u64::MAX));
+    let Some(expiry) = now.checked_add(ttl.min(MAX_EXPIRE)) else {
+        return Some(Value::Error(format!("ERR invalid expire time in '{}' command", name)));
+    };
+    shard.set_expiry(&key, expiry);
+    Some(Value::Integer(1))
+}
 
-    #[tokio::test]
-    async fn test_set_with_ttl() {
-        let db = new_db();
-        let cmd = vec![
-            Value::BulkString(Bytes::from("SET")),
-            Value::BulkString(Bytes::from("key")),
-            Value::BulkString(Bytes::from("value")),
-            Value::BulkString(Bytes::from("EX")),
-            Value::BulkString(Bytes::from("1")),
-        ];
-        let resp = handle_command(&db, &cmd).await;
-        assert_eq!(resp, Some(CommandResult::Value(Value::SimpleString("OK".to_string()))));
+/// Clears a key's expiry; 0 if the key is missing or had none
+async fn handle_persist(db: &Db, args: &[Value]) -> Option<Value> {
+    let key = extract_string(&args[0])?;
+    let mut shard = db.shard(&key).write().await;
+    let cleared = shard.get_live(&key).is_some() && shard.clear_expiry(&key);
+    Some(Value::Integer(cleared as i64))
+}
 
-        // Check expiry is set
-        {
-            let db_lock = db.read().await;
-            if let Some(db_val) = db_lock.data.get("key") {
-                assert!(db_val.expiry.is_some());
-            } else {
-                panic!("Key not found");
-            }
-        }
-    }
+async fn handle_ttl(db: &Db, args: &[Value]) -> Option<Value> {
+    let key = extract_string(&args[0])?;
+    let shard = db.shard(&key).read().await;
+    let now = shard.now();
+    let ttl = match shard.get(&key) {
+        Some(v) if v.is_expired(now) => -2,
+        Some(v) => match v.expiry {
+            Some(expiry) => expiry.duration_since(now).as_secs() as i64,
+            None => -1,
+        },
+        None => -2,
+    };
+    Some(Value::Integer(ttl))
+}
 
-    #[tokio::test]
-    async fn test_get_expired() {
-        let db = new_db();
-        // Manually insert expired value
-        {
-            let mut db_lock = db.write().await;
-            let mut val = DbValue::new(Bytes::from("value"));
-            val.expiry = Some(std::time::Instant::now() - std::time::Duration::from_secs(1));
-            db_lock.data.insert("key".to_string(), val);
+/// Counts how many of the given keys exist; a key named twice counts twice
+async fn handle_exists(db: &Db, args: &[Value]) -> Option<Value> {
+    let keys = args.iter().map(extract_string).collect::<Option<Vec<_>>>()?;
+    let mut shards = db.lock_shards(keys.iter().map(String::as_str)).await;
+    let now = db.now();
+    let count = keys.iter()
+        .filter(|key| shards.get_mut(key).get(key).is_some_and(|v| !v.is_expired(now)))
+        .count();
+    Some(Value::Integer(count as i64))
+}
+
+async fn handle_lpush(db: &Db, args: &[Value]) -> Option<Value> {
+    push(db, args, true).await
+}
+
+async fn handle_rpush(db: &Db, args: &[Value]) -> Option<Value> {
+    push(db, args, false).await
+}
+
+/// LPUSH/RPUSH: `args` is the key followed by the elements. Elements pushed to the
+/// head go in one at a time, so the last one given ends up first.
+async fn push(db: &Db, args: &[Value], to_head: bool) -> Option<Value> {
+    let key = extract_string(&args[0])?;
+    let elements = args[1..].iter().map(extract_bytes).collect::<Option<Vec<_>>>()?;
+    let mut shard = db.shard(&key).write().await;
+    let list = match shard.get_live_mut(&key).map(|v| &mut v.data) {
+        Some(DataType::List(list)) => list,
+        Some(_) => return Some(wrongtype()),
+        None => {
+            let len = elements.len();
+            let list = if to_head { elements.into_iter().rev().collect() } else { elements };
+            shard.insert(key, DbValue::new_list(list));
+            return Some(Value::Integer(len as i64));
         }
+    };
+    if to_head {
+        list.splice(0..0, elements.into_iter().rev());
+    } else {
+        list.extend(elements);
+    }
+    Some(Value::Integer(list.len() as i64))
+}
 
-        let cmd_get = vec![
-            Value::BulkString(Bytes::from("GET")),
-            Value::BulkString(Bytes::from("key")),
-        ];
-        let resp_get = handle_command(&db, &cmd_get).await;
-        assert_eq!(resp_get, Some(CommandResult::Value(Value::Null)));
+async fn handle_llen(db: &Db, args: &[Value]) -> Option<Value> {
+    let key = extract_string(&args[0])?;
+    let mut shard = db.shard(&key).write().await;
+    match shard.get_live(&key).map(|v| &v.data) {
+        Some(DataType::List(list)) => Some(Value::Integer(list.len() as i64)),
+        Some(_) => Some(wrongtype()),
+        None => Some(Value::Integer(0)),
     }
+}
 
-    #[tokio::test]
-    async fn test_del() {
+async fn handle_lindex(db: &Db, args: &[Value]) -> Option<Value> {
+    let key = extract_string(&args[0])?;
+    let index = match parse_i64_arg(&args[1]) {
+        Ok(index) => index,
+        Err(e) => return Some(e),
+    };
+    let mut shard = db.shard(&key).write().await;
+    let list = match shard.get_live(&key).map(|v| &v.data) {
+        Some(DataType::List(list)) => list,
+        Some(_) => return Some(wrongtype()),
+        None => return Some(Value::Null),
+    };
+    // Negative indexes count back from the tail
+    let index = if index < 0 { index + list.len() as i64 } else { index };
+    let element = usize::try_from(index).ok().and_then(|i| list.get(i));
+    Some(element.map_or(Value::Null, |e| Value::BulkString(e.clone())))
+}
+
+async fn handle_lpop(db: &Db, args: &[Value]) -> Option<Value> {
+    pop(db, args, true).await
+}
+
+async fn handle_rpop(db: &Db, args: &[Value]) -> Option<Value> {
+    pop(db, args, false).await
+}
+
+/// LPOP/RPOP `key [count]`. Without a count the reply is one element, with a count
+/// it's an array of up to that many.
+async fn pop(db: &Db, args: &[Value], from_head: bool) -> Option<Value> {
+    if args.len() > 2 {
+        return Some(wrong_arity(if from_head { "lpop" } else { "rpop" }));
+    }
+    let key = extract_string(&args[0])?;
+    let count = match args.get(1).map(parse_usize_arg) {
+        None => None,
+        Some(Ok(n)) => Some(n),
+        Some(Err(e)) => return Some(e),
+    };
+    let mut shard = db.shard(&key).write().await;
+    let list = match shard.get_live_mut(&key).map(|v| &mut v.data) {
+        Some(DataType::List(list)) => list,
+        Some(_) => return Some(wrongtype()),
+        None => return Some(Value::Null),
+    };
+    let n = count.unwrap_or(1).min(list.len());
+    let popped: Vec<Bytes> = if from_head {
+        list.drain(..n).collect()
+    } else {
+        list.drain(list.len() - n..).rev().collect()
+    };
+    remove_if_empty(&mut shard, &key);
+    match count {
+        Some(_) => Some(Value::Array(popped.into_iter().map(Value::BulkString).collect())),
+        None => Some(popped.into_iter().next().map_or(Value::Null, Value::BulkString)),
+    }
+}
+
+/// Collections never stay around empty: call this after anything that can remove
+/// the last element of the collection at `key`
+fn remove_if_empty(shard: &mut Shard, key: &str) {
+    let empty = match shard.get(key).map(|v| &v.data) {
+        Some(DataType::List(list)) => list.is_empty(),
+        _ => false,
+    };
+    if empty {
+        shard.remove(key);
+    }
+}
+
+async fn handle_lmpop(db: &Db, args: &[Value]) -> Option<Value> {
+    let numkeys = match parse_usize_arg(&args[0]) {
+        Ok(0) => return Some(Value::Error("ERR numkeys should be greater than 0".to_string())),
+        Ok(n) => n,
+        Err(e) => return Some(e),
+    };
+    if numkeys > args.len() - 2 {
+        return Some(syntax_error());
+    }
+    let keys = &args[1..=numkeys];
+    let from_left = match extract_string(&args[numkeys + 1])?.to_uppercase().as_str() {
+        "LEFT" => true,
+        "RIGHT" => false,
+        _ => return Some(syntax_error()),
+    };
+    let count = match &args[numkeys + 2..] {
+        [] => 1,
+        [opt, n] if extract_string(opt)?.eq_ignore_ascii_case("COUNT") => {
+            match parse_usize_arg(n) {
+                Ok(0) => return Some(Value::Error("ERR count should be greater than 0".to_string())),
+                Ok(n) => n,
+                Err(e) => return Some(e),
+            }
+        }
+        _ => return Some(syntax_error()),
+    };
+
+    let keys = keys.iter().map(extract_string).collect::<Option<Vec<_>>>()?;
+    let mut shards = db.lock_shards(keys.iter().map(String::as_str)).await;
+    for key in keys {
+        let shard = shards.get_mut(&key);
+        let list = match shard.get_live_mut(&key).map(|v| &mut v.data) {
+            Some(DataType::List(list)) if !list.is_empty() => list,
+            Some(DataType::List(_)) | None => continue,
+            Some(_) => return Some(wrongtype()),
+        };
+        let n = count.min(list.len());
+        let popped: Vec<Value> = if from_left {
+            list.drain(..n).map(Value::BulkString).collect()
+        } else {
+            list.drain(list.len() - n..).rev().map(Value::BulkString).collect()
+        };
+        remove_if_empty(shard, &key);
+        return Some(Value::Array(vec![
+            Value::BulkString(Bytes::from(key)),
+            Value::Array(popped),
+        ]));
+    }
+    Some(Value::Null)
+}
+
+async fn handle_info(db: &Db, args: &[Value]) -> Option<Value> {
+    let wanted = args.iter().map(|a| extract_string(a).map(|s| s.to_lowercase())).collect::<Option<Vec<_>>>()?;
+    let show_all = wanted.is_empty() || wanted.iter().any(|s| matches!(s.as_str(), "all" | "default" | "everything"));
+
+    let sections = [
+        ("server", format!(
+            "# Server\r\nredis_mode:standalone\r\nprocess_id:{}\r\nrun_id:{}\r\n",
+            std::process::id(),
+            db.run_id
+        )),
+        ("replication", format!(
+            "# Replication\r\nrole:master\r\nconnected_slaves:0\r\nmaster_failover_state:no-failover\r\n\
+             master_replid:{}\r\nmaster_repl_offset:0\r\n",
+            db.replid
+        )),
+        // There is no RDB or AOF persistence, so nothing is ever loading or saving
+        ("persistence", format!(
+            "# Persistence\r\nloading:0\r\nrdb_bgsave_in_progress:0\r\nrdb_last_save_time:{}\r\n\
+             aof_enabled:0\r\naof_rewrite_in_progress:0\r\n",
+            db.last_save_time
+        )),
+    ];
+    let info: Vec<String> = sections.into_iter()
+        .filter(|(name, _)| show_all || wanted.iter().any(|w| w == name))
+        .map(|(_, body)| body)
+        .collect();
+    Some(Value::BulkString(Bytes::from(info.join("\r\n"))))
+}
+
+async fn handle_lcs(db: &Db, args: &[Value]) -> Option<Value> {
+    let (mut want_len, mut want_idx, mut with_match_len, mut min_match_len) = (false, false, false, 0);
+    let mut i = 2;
+    while i < args.len() {
+        match extract_string(&args[i])?.to_uppercase().as_str() {
+            "LEN" => want_len = true,
+            "IDX" => want_idx = true,
+            "WITHMATCHLEN" => with_match_len = true,
+            "MINMATCHLEN" if i + 1 < args.len() => {
+                i += 1;
+                min_match_len = match parse_i64_arg(&args[i]) {
+                    Ok(n) => n.max(0) as usize,
+                    Err(e) => return Some(e),
+                };
+            }
+            _ => return Some(syntax_error()),
+        }
+        i += 1;
+    }
+    if want_len && want_idx {
+        return Some(Value::Error("ERR If you want both the length and indexes, please just use IDX.".to_string()));
+    }
+
+    let (a, b) = {
+        let keys = args[..2].iter().map(extract_string).collect::<Option<Vec<_>>>()?;
+        let mut shards = db.lock_shards(keys.iter().map(String::as_str)).await;
+        let now = db.now();
+        let mut strings = Vec::with_capacity(2);
+        for key in &keys {
+            match shards.get_mut(key).get(key).filter(|v| !v.is_expired(now)).map(|v| &v.data) {
+                Some(DataType::String(bs)) => strings.push(bs.clone()),
+                None => strings.push(Bytes::new()),
+                Some(_) => {
+                    return Some(Value::Error("ERR The specified keys must contain string values".to_string()))
+                }
+            }
+        }
+        (strings.swap_remove(0), strings.swap_remove(0))
+    };
+
+    let table_bytes = (a.len() + 1)
+        .checked_mul(b.len() + 1)
+        .and_then(|cells| cells.checked_mul(size_of::<u32>()));
+    if table_bytes.is_none_or(|bytes| bytes > LCS_MAX_TABLE_BYTES) {
+        return Some(Value::Error("ERR String too long for LCS".to_string()));
+    }
+    // The table fill is O(len(a) * len(b)), too slow to run on a runtime thread
+    let reply = tokio::task::spawn_blocking(move || {
+        lcs_reply(&a, &b, want_len, want_idx, with_match_len, min_match_len)
+    });
+    Some(reply.await.unwrap_or_else(|e| std::panic::resume_unwind(e.into_panic())))
+}
+
+/// Largest LCS table, in bytes, that LCS will allocate
+const LCS_MAX_TABLE_BYTES: usize = 256 * 1024 * 1024;
+
+/// Builds the LCS reply for `a` and `b` in the form the options ask for
+fn lcs_reply(a: &[u8], b: &[u8], want_len: bool, want_idx: bool, with_match_len: bool, min_match_len: usize) -> Value {
+    // dp[i * width + j] is the LCS length of a[..i] and b[..j]. The table size
+    // cap keeps both lengths well within u32.
+    let width = b.len() + 1;
+    let mut dp = vec![0u32; (a.len() + 1) * width];
+    for i in 1..=a.len() {
+        for j in 1..=b.len() {
+            dp[i * width + j] = if a[i - 1] == b[j - 1] {
+                dp[(i - 1) * width + j - 1] + 1
+            } else {
+                dp[(i - 1) * width + j].max(dp[i * width + j - 1])
+            };
+        }
+    }
+    let len = dp[a.len() * width + b.len()] as usize;
+    if want_len {
+        return Value::Integer(len as i64);
+    }
+
+    // Walk back from the end, collecting the subsequence and the contiguous
+    // ranges it was matched in (so ranges come out last-first, as in Redis)
+    let mut lcs = Vec::with_capacity(len);
+    let mut matches = Vec::new();
+    let mut current: Option<(usize, usize, usize)> = None; // (a start, b start, length)
+    let mut emit = |range: Option<(usize, usize, usize)>| {
+        if let Some((a_start, b_start, n)) = range {
+            if n >= min_match_len {
+                let span = |start: usize| Value::Array(vec![
+                    Value::Integer(start as i64),
+                    Value::Integer((start + n - 1) as i64),
+                ]);
+                let mut entry = vec![span(a_start), span(b_start)];
+                if with_match_len {
+                    entry.push(Value::Integer(n as i64));
+                }
+                matches.push(Value::Array(entry));
+            }
+        }
+    };
+    let (mut i, mut j) = (a.len(), b.len());
+    while i > 0 && j > 0 {
+        if a[i - 1] == b[j - 1] {
+            lcs.push(a[i - 1]);
+            current = Some((i - 1, j - 1, current.map_or(1, |(_, _, n)| n + 1)));
+            i -= 1;
+            j -= 1;
+        } else {
+            if dp[(i - 1) * width + j] > dp[i * width + j - 1] {
+                i -= 1;
+            } else {
+                j -= 1;
+            }
+            emit(current.take());
+        }
+    }
+    emit(current.take());
+
+    if !want_idx {
+        lcs.reverse();
+        return Value::BulkString(Bytes::from(lcs));
+    }
+    Value::Array(vec![
+        Value::BulkString(Bytes::from("matches")),
+        Value::Array(matches),
+        Value::BulkString(Bytes::from("len")),
+        Value::Integer(len as i64),
+    ])
+}
+
+/// The error a RESP2 connection in subscribed state gets for anything but the
+/// pub/sub commands, PING, QUIT and RESET. Returns None when `cmd` is allowed.
+pub fn subscribed_context_error(cmd: &[Value]) -> Option<Value> {
+    let name = extract_string(cmd.first()?)?;
+    match name.to_uppercase().as_str() {
+        "SUBSCRIBE" | "UNSUBSCRIBE" | "PSUBSCRIBE" | "PUNSUBSCRIBE" | "SSUBSCRIBE" | "SUNSUBSCRIBE"
+        | "PING" | "QUIT" | "RESET" => None,
+        _ => Some(Value::Error(format!(
+            "ERR Can't execute '{}': only (P|S)SUBSCRIBE / (P|S)UNSUBSCRIBE / PING / QUIT / RESET are allowed in this context",
+            name.to_lowercase()
+        ))),
+    }
+}
+
+/// Holds the caller until any CLIENT PAUSE covering `cmd` has ended.
+/// CLIENT itself is never held so that CLIENT UNPAUSE can get through.
+pub async fn wait_if_paused(db: &Db, cmd: &[Value]) {
+    let spec = match cmd.first() {
+        Some(Value::BulkString(name)) => lookup_command_bytes(name),
+        _ => return,
+    };
+    if spec.is_some_and(|spec| spec.name == "CLIENT") {
+        return;
+    }
+
+    loop {
+        let pause = db.pause.read().await;
+        let deadline = match pause.deadline {
+            Some(deadline) if deadline > Instant::now()
+                && (pause.mode == PauseMode::All || spec.is_some_and(|spec| spec.is_write())) => deadline,
+            _ => return,
+        };
+        // Register for the wakeup before releasing the lock so an UNPAUSE can't slip in between
+        let notify = pause.notify.clone();
+        let notified = notify.notified();
+        tokio::pin!(notified);
+        notified.as_mut().enable();
+        drop(pause);
+
+        tokio::select! {
+            _ = notified => {}
+            _ = tokio::time::sleep_until(deadline.into()) => {}
+        }
+    }
+}
+
+async fn handle_client(db: &Db, state: &mut ConnectionState, args: &[Value]) -> Option<Value> {
+    let subcommand = extract_string(args.first()?)?;
+    match subcommand.to_uppercase().as_str() {
+        "PAUSE" => {
+            if args.len() < 2 || args.len() > 3 {
+                return Some(wrong_arity("client|pause"));
+            }
+            let ms = match parse_usize_arg(&args[1]) {
+                Ok(ms) => ms as u64,
+                Err(e) => return Some(e),
+            };
+            let mode = match args.get(2) {
+                None => PauseMode::All,
+                Some(v) => match extract_string(v)?.to_uppercase().as_str() {
+                    "ALL" => PauseMode::All,
+                    "WRITE" => PauseMode::Write,
+                    _ => return Some(syntax_error()),
+                },
+            };
+            // As in Redis, the pause has to end at a Unix time in ms that fits an i64
+            let deadline = (ms as i64)
+                .checked_add(unix_time_millis() as i64)
+                .and_then(|_| Instant::now().checked_add(Duration::from_millis(ms)));
+            let Some(deadline) = deadline else {
+                return Some(Value::Error("ERR timeout is out of range".to_string()));
+            };
+            let mut pause = db.pause.write().await;
+            pause.deadline = Some(deadline);
+            pause.mode = mode;
+            Some(Value::SimpleString("OK".to_string()))
+        }
+        "UNPAUSE" => {
+            if args.len() != 1 {
+                return Some(wrong_arity("client|unpause"));
+            }
+            let mut pause = db.pause.write().await;
+            pause.deadline = None;
+            pause.notify.notify_waiters();
+            Some(Value::SimpleString("OK".to_string()))
+        }
+        "ID" if args.len() == 1 => Some(Value::Integer(state.id as i64)),
+        "GETNAME" if args.len() == 1 => Some(state.name.clone().map_or(Value::Null, Value::BulkString)),
+        "SETNAME" if args.len() == 2 => {
+            let name = extract_bytes(&args[1])?;
+            // Names show up in CLIENT LIST, which separates fields with spaces
+            if name.iter().any(|&b| !(b'!'..=b'~').contains(&b)) {
+                return Some(Value::Error(
+                    "ERR Client names cannot contain spaces, newlines or special characters.".to_string(),
+                ));
+            }
+            state.name = if name.is_empty() { None } else { Some(name) };
+            Some(Value::SimpleString("OK".to_string()))
+        }
+        "ID" | "GETNAME" | "SETNAME" => Some(wrong_arity(&format!("client|{}", subcommand.to_lowercase()))),
+        _ => Some(unknown_subcommand("CLIENT", &subcommand)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ServerConfig;
+    use crate::db::{new_db, new_db_with_clock, new_db_with_config, MockClock};
+    use std::sync::Arc;
+    use tokio::sync::mpsc;
+
+    async fn insert(db: &Db, key: &str, value: DbValue) {
+        db.shard(key).write().await.insert(key.to_string(), value);
+    }
+
+    #[tokio::test]
+    async fn test_set_get() {
+        let db = new_db();
+        let cmd = vec![
+            Value::BulkString(Bytes::from("SET")),
+            Value::BulkString(Bytes::from("key")),
+            Value::BulkString(Bytes::from("value")),
+        ];
+        let resp = handle_command(&db, &mut ConnectionState::new(), &cmd).await;
+        assert_eq!(resp, Some(CommandResult::Value(Value::SimpleString("OK".to_string()))));
+
+        let cmd_get = vec![
+            Value::BulkString(Bytes::from("GET")),
+            Value::BulkString(Bytes::from("key")),
+        ];
+        let resp_get = handle_command(&db, &mut ConnectionState::new(), &cmd_get).await;
+        assert_eq!(resp_get, Some(CommandResult::Value(Value::BulkString(Bytes::from("value")))));
+    }
+
+    #[tokio::test]
+    async fn test_get_wrong_type() {
+        let db = new_db();
+        db.shard("list").write().await.insert("list".to_string(), DbValue::new_list(vec![Bytes::from("a")]));
+        let cmd_get = vec![
+            Value::BulkString(Bytes::from("GET")),
+            Value::BulkString(Bytes::from("list")),
+        ];
+        let resp = handle_command(&db, &mut ConnectionState::new(), &cmd_get).await;
+        assert_eq!(resp, Some(CommandResult::Value(wrongtype())));
+    }
+
+    #[tokio::test]
+    async fn test_append() {
+        let db = new_db();
+        let append = |key: &'static str, value: &'static str| vec![
+            Value::BulkString(Bytes::from("APPEND")),
+            Value::BulkString(Bytes::from(key)),
+            Value::BulkString(Bytes::from(value)),
+        ];
+
+        let resp = handle_command(&db, &mut ConnectionState::new(), &append("key", "Hello")).await;
+        assert_eq!(resp, Some(CommandResult::Value(Value::Integer(5))));
+        let resp = handle_command(&db, &mut ConnectionState::new(), &append("key", " World")).await;
+        assert_eq!(resp, Some(CommandResult::Value(Value::Integer(11))));
+
+        let cmd_get = vec![
+            Value::BulkString(Bytes::from("GET")),
+            Value::BulkString(Bytes::from("key")),
+        ];
+        let resp = handle_command(&db, &mut ConnectionState::new(), &cmd_get).await;
+        assert_eq!(resp, Some(CommandResult::Value(Value::BulkString(Bytes::from("Hello World")))));
+
+        db.shard("list").write().await.insert("list".to_string(), DbValue::new_list(vec![Bytes::from("a")]));
+        let resp = handle_command(&db, &mut ConnectionState::new(), &append("list", "x")).await;
+        assert_eq!(resp, Some(CommandResult::Value(wrongtype())));
+    }
+
+    #[tokio::test]
+    async fn test_strlen() {
+        let clock = Arc::new(MockClock::new());
+        let db = new_db_with_clock(clock.clone());
+        let strlen = |key: &'static str| vec![
+            Value::BulkString(Bytes::from("STRLEN")),
+            Value::BulkString(Bytes::from(key)),
+        ];
+        let cmd_set = vec![
+            Value::BulkString(Bytes::from("SET")),
+            Value::BulkString(Bytes::from("key")),
+            Value::BulkString(Bytes::from("value")),
+            Value::BulkString(Bytes::from("EX")),
+            Value::BulkString(Bytes::from("10")),
+        ];
+        handle_command(&db, &mut ConnectionState::new(), &cmd_set).await;
+
+        let resp = handle_command(&db, &mut ConnectionState::new(), &strlen("key")).await;
+        assert_eq!(resp, Some(CommandResult::Value(Value::Integer(5))));
+        let resp = handle_command(&db, &mut ConnectionState::new(), &strlen("missing")).await;
+        assert_eq!(resp, Some(CommandResult::Value(Value::Integer(0))));
+
+        clock.advance(Duration::from_secs(11));
+        let resp = handle_command(&db, &mut ConnectionState::new(), &strlen("key")).await;
+        assert_eq!(resp, Some(CommandResult::Value(Value::Integer(0))));
+
+        db.shard("list").write().await.insert("list".to_string(), DbValue::new_list(vec![Bytes::from("a")]));
+        let resp = handle_command(&db, &mut ConnectionState::new(), &strlen("list")).await;
+        assert_eq!(resp, Some(CommandResult::Value(wrongtype())));
+    }
+
+    #[tokio::test]
+    async fn test_set_strict_type() {
+        let db = new_db();
+        let cmd_set = vec![
+            Value::BulkString(Bytes::from("SET")),
+            Value::BulkString(Bytes::from("list")),
+            Value::BulkString(Bytes::from("value")),
+        ];
+
+        // By default SET replaces a value of any type, as in Redis
+        db.shard("list").write().await.insert("list".to_string(), DbValue::new_list(vec![Bytes::from("a")]));
+        let resp = handle_command(&db, &mut ConnectionState::new(), &cmd_set).await;
+        assert_eq!(resp, Some(CommandResult::Value(Value::SimpleString("OK".to_string()))));
+
+        let db = new_db_with_config(&ServerConfig { set_strict_type: true, ..Default::default() });
+        db.shard("list").write().await.insert("list".to_string(), DbValue::new_list(vec![Bytes::from("a")]));
+        let resp = handle_command(&db, &mut ConnectionState::new(), &cmd_set).await;
+        assert_eq!(resp, Some(CommandResult::Value(wrongtype())));
+        assert_eq!(db.shard("list").read().await.get("list").unwrap().data, DataType::List(vec![Bytes::from("a")]));
+    }
+
+    #[tokio::test]
+    async fn test_set_with_ttl() {
+        let db = new_db();
+        let cmd = vec![
+            Value::BulkString(Bytes::from("SET")),
+            Value::BulkString(Bytes::from("key")),
+            Value::BulkString(Bytes::from("value")),
+            Value::BulkString(Bytes::from("EX")),
+            Value::BulkString(Bytes::from("1")),
+        ];
+        let resp = handle_command(&db, &mut ConnectionState::new(), &cmd).await;
+        assert_eq!(resp, Some(CommandResult::Value(Value::SimpleString("OK".to_string()))));
+
+        // Check expiry is set
+        {
+            let shard = db.shard("key").read().await;
+            if let Some(db_val) = shard.get("key") {
+                assert!(db_val.expiry.is_some());
+            } else {
+                panic!("Key not found");
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_get_expired() {
+        let db = new_db();
+        // Manually insert expired value
+        {
+            let mut val = DbValue::new_string(Bytes::from("value"));
+            val.expiry = Some(std::time::Instant::now() - std::time::Duration::from_secs(1));
+            db.shard("key").write().await.insert("key".to_string(), val);
+        }
+
+        let cmd_get = vec![
+            Value::BulkString(Bytes::from("GET")),
+            Value::BulkString(Bytes::from("key")),
+        ];
+        let resp_get = handle_command(&db, &mut ConnectionState::new(), &cmd_get).await;
+        assert_eq!(resp_get, Some(CommandResult::Value(Value::Null)));
+    }
+
+    #[tokio::test]
+    async fn test_del() {
         let db = new_db();
         // Set first
         let cmd_set = vec![
@@ -279,13 +1354,13 @@ mod tests {
             Value::BulkString(Bytes::from("key")),
             Value::BulkString(Bytes::from("value")),
         ];
-        handle_command(&db, &cmd_set).await;
+        handle_command(&db, &mut ConnectionState::new(), &cmd_set).await;
 
         let cmd_del = vec![
             Value::BulkString(Bytes::from("DEL")),
             Value::BulkString(Bytes::from("key")),
         ];
-        let resp = handle_command(&db, &cmd_del).await;
+        let resp = handle_command(&db, &mut ConnectionState::new(), &cmd_del).await;
         assert_eq!(resp, Some(CommandResult::Value(Value::Integer(1))));
 
         // Get after del
@@ -293,15 +1368,33 @@ mod tests {
             Value::BulkString(Bytes::from("GET")),
             Value::BulkString(Bytes::from("key")),
         ];
-        let resp_get = handle_command(&db, &cmd_get).await;
+        let resp_get = handle_command(&db, &mut ConnectionState::new(), &cmd_get).await;
         assert_eq!(resp_get, Some(CommandResult::Value(Value::Null)));
     }
 
+    #[tokio::test]
+    async fn test_del_expired_key() {
+        let clock = Arc::new(MockClock::new());
+        let db = new_db_with_clock(clock.clone());
+        let mut expiring = DbValue::new_string(Bytes::from("v"));
+        expiring.expiry = Some(db.now() + Duration::from_secs(1));
+        insert(&db, "key", expiring).await;
+        clock.advance(Duration::from_secs(2));
+
+        let cmd_del = vec![
+            Value::BulkString(Bytes::from("DEL")),
+            Value::BulkString(Bytes::from("key")),
+        ];
+        let resp = handle_command(&db, &mut ConnectionState::new(), &cmd_del).await;
+        assert_eq!(resp, Some(CommandResult::Value(Value::Integer(0))));
+        assert!(!db.shard("key").read().await.contains_key("key"));
+    }
+
     #[tokio::test]
     async fn test_ping() {
         let db = new_db();
         let cmd = vec![Value::BulkString(Bytes::from("PING"))];
-        let resp = handle_command(&db, &cmd).await;
+        let resp = handle_command(&db, &mut ConnectionState::new(), &cmd).await;
         assert_eq!(resp, Some(CommandResult::Value(Value::SimpleString("PONG".to_string()))));
     }
 
@@ -314,13 +1407,13 @@ mod tests {
             Value::BulkString(Bytes::from("num")),
             Value::BulkString(Bytes::from("5")),
         ];
-        handle_command(&db, &cmd_set).await;
+        handle_command(&db, &mut ConnectionState::new(), &cmd_set).await;
 
         let cmd_incr = vec![
             Value::BulkString(Bytes::from("INCR")),
             Value::BulkString(Bytes::from("num")),
         ];
-        let resp = handle_command(&db, &cmd_incr).await;
+        let resp = handle_command(&db, &mut ConnectionState::new(), &cmd_incr).await;
         assert_eq!(resp, Some(CommandResult::Value(Value::Integer(6))));
 
         // Check value
@@ -328,7 +1421,7 @@ mod tests {
             Value::BulkString(Bytes::from("GET")),
             Value::BulkString(Bytes::from("num")),
         ];
-        let resp_get = handle_command(&db, &cmd_get).await;
+        let resp_get = handle_command(&db, &mut ConnectionState::new(), &cmd_get).await;
         assert_eq!(resp_get, Some(CommandResult::Value(Value::BulkString(Bytes::from("6")))));
     }
 
@@ -339,10 +1432,108 @@ mod tests {
             Value::BulkString(Bytes::from("DECR")),
             Value::BulkString(Bytes::from("num")),
         ];
-        let resp = handle_command(&db, &cmd_decr).await;
+        let resp = handle_command(&db, &mut ConnectionState::new(), &cmd_decr).await;
         assert_eq!(resp, Some(CommandResult::Value(Value::Integer(-1))));
     }
 
+    #[tokio::test]
+    async fn test_incr_missing_key_creates_string() {
+        let db = new_db();
+        let cmd_incr = vec![
+            Value::BulkString(Bytes::from("INCR")),
+            Value::BulkString(Bytes::from("num")),
+        ];
+        let resp = handle_command(&db, &mut ConnectionState::new(), &cmd_incr).await;
+        assert_eq!(resp, Some(CommandResult::Value(Value::Integer(1))));
+
+        let cmd_type = vec![
+            Value::BulkString(Bytes::from("TYPE")),
+            Value::BulkString(Bytes::from("num")),
+        ];
+        let resp = handle_command(&db, &mut ConnectionState::new(), &cmd_type).await;
+        assert_eq!(resp, Some(CommandResult::Value(Value::SimpleString("string".to_string()))));
+    }
+
+    #[tokio::test]
+    async fn test_append_missing_key_creates_string() {
+        let db = new_db();
+        let cmd = |name: &'static str| vec![
+            Value::BulkString(Bytes::from(name)),
+            Value::BulkString(Bytes::from("key")),
+        ];
+        let mut cmd_append = cmd("APPEND");
+        cmd_append.push(Value::BulkString(Bytes::from("abc")));
+        let resp = handle_command(&db, &mut ConnectionState::new(), &cmd_append).await;
+        assert_eq!(resp, Some(CommandResult::Value(Value::Integer(3))));
+
+        let resp = handle_command(&db, &mut ConnectionState::new(), &cmd("TYPE")).await;
+        assert_eq!(resp, Some(CommandResult::Value(Value::SimpleString("string".to_string()))));
+        let resp = handle_command(&db, &mut ConnectionState::new(), &cmd("GET")).await;
+        assert_eq!(resp, Some(CommandResult::Value(Value::BulkString(Bytes::from("abc")))));
+    }
+
+    #[tokio::test]
+    async fn test_incrby_decrby() {
+        let db = new_db();
+        let cmd = |name: &'static str, delta: &'static str| vec![
+            Value::BulkString(Bytes::from(name)),
+            Value::BulkString(Bytes::from("num")),
+            Value::BulkString(Bytes::from(delta)),
+        ];
+
+        let resp = handle_command(&db, &mut ConnectionState::new(), &cmd("INCRBY", "10")).await;
+        assert_eq!(resp, Some(CommandResult::Value(Value::Integer(10))));
+        let resp = handle_command(&db, &mut ConnectionState::new(), &cmd("DECRBY", "15")).await;
+        assert_eq!(resp, Some(CommandResult::Value(Value::Integer(-5))));
+
+        let resp = handle_command(&db, &mut ConnectionState::new(), &cmd("INCRBY", "ten")).await;
+        assert_eq!(resp, Some(CommandResult::Value(not_integer())));
+
+        let resp = handle_command(&db, &mut ConnectionState::new(), &cmd("INCRBY", "9223372036854775807")).await;
+        assert_eq!(resp, Some(CommandResult::Value(Value::Integer(i64::MAX - 5))));
+        let resp = handle_command(&db, &mut ConnectionState::new(), &cmd("INCRBY", "6")).await;
+        assert_eq!(resp, Some(CommandResult::Value(Value::Error(INCR_OVERFLOW.to_string()))));
+
+        let resp = handle_command(&db, &mut ConnectionState::new(), &cmd("DECRBY", "-9223372036854775808")).await;
+        assert_eq!(resp, Some(CommandResult::Value(Value::Error("ERR decrement would overflow".to_string()))));
+    }
+
+    #[tokio::test]
+    async fn test_incr_decr_errors() {
+        let db = new_db();
+        let set = |value: &'static str| vec![
+            Value::BulkString(Bytes::from("SET")),
+            Value::BulkString(Bytes::from("num")),
+            Value::BulkString(Bytes::from(value)),
+        ];
+        let cmd = |name: &'static str| vec![
+            Value::BulkString(Bytes::from(name)),
+            Value::BulkString(Bytes::from("num")),
+        ];
+
+        handle_command(&db, &mut ConnectionState::new(), &set("abc")).await;
+        let resp = handle_command(&db, &mut ConnectionState::new(), &cmd("INCR")).await;
+        assert_eq!(resp, Some(CommandResult::Value(not_integer())));
+        let resp = handle_command(&db, &mut ConnectionState::new(), &cmd("DECR")).await;
+        assert_eq!(resp, Some(CommandResult::Value(not_integer())));
+
+        handle_command(&db, &mut ConnectionState::new(), &set("9223372036854775807")).await;
+        let resp = handle_command(&db, &mut ConnectionState::new(), &cmd("INCR")).await;
+        assert_eq!(resp, Some(CommandResult::Value(Value::Error(INCR_OVERFLOW.to_string()))));
+
+        handle_command(&db, &mut ConnectionState::new(), &set("-9223372036854775808")).await;
+        let resp = handle_command(&db, &mut ConnectionState::new(), &cmd("DECR")).await;
+        assert_eq!(resp, Some(CommandResult::Value(Value::Error(INCR_OVERFLOW.to_string()))));
+
+        // The failed DECR left the value alone
+        let resp = handle_command(&db, &mut ConnectionState::new(), &cmd("GET")).await;
+        assert_eq!(resp, Some(CommandResult::Value(Value::BulkString(Bytes::from("-9223372036854775808")))));
+
+        db.shard("num").write().await.insert("num".to_string(), DbValue::new_list(vec![Bytes::from("1")]));
+        let resp = handle_command(&db, &mut ConnectionState::new(), &cmd("INCR")).await;
+        assert_eq!(resp, Some(CommandResult::Value(wrongtype())));
+    }
+
     #[tokio::test]
     async fn test_exists() {
         let db = new_db();
@@ -350,7 +1541,7 @@ mod tests {
             Value::BulkString(Bytes::from("EXISTS")),
             Value::BulkString(Bytes::from("key")),
         ];
-        let resp = handle_command(&db, &cmd_exists).await;
+        let resp = handle_command(&db, &mut ConnectionState::new(), &cmd_exists).await;
         assert_eq!(resp, Some(CommandResult::Value(Value::Integer(0))));
 
         // Set key
@@ -359,35 +1550,79 @@ mod tests {
             Value::BulkString(Bytes::from("key")),
             Value::BulkString(Bytes::from("value")),
         ];
-        handle_command(&db, &cmd_set).await;
+        handle_command(&db, &mut ConnectionState::new(), &cmd_set).await;
 
-        let resp_exists = handle_command(&db, &cmd_exists).await;
+        let resp_exists = handle_command(&db, &mut ConnectionState::new(), &cmd_exists).await;
         assert_eq!(resp_exists, Some(CommandResult::Value(Value::Integer(1))));
     }
 
     #[tokio::test]
-    async fn test_keys() {
+    async fn test_exists_multiple_keys() {
         let db = new_db();
-        // Set some keys
-        let cmd_set1 = vec![
-            Value::BulkString(Bytes::from("SET")),
-            Value::BulkString(Bytes::from("key1")),
-            Value::BulkString(Bytes::from("value1")),
+        for key in ["a", "b"] {
+            let cmd_set = vec![
+                Value::BulkString(Bytes::from("SET")),
+                Value::BulkString(Bytes::from(key)),
+                Value::BulkString(Bytes::from("value")),
+            ];
+            handle_command(&db, &mut ConnectionState::new(), &cmd_set).await;
+        }
+
+        let cmd_exists = vec![
+            Value::BulkString(Bytes::from("EXISTS")),
+            Value::BulkString(Bytes::from("a")),
+            Value::BulkString(Bytes::from("missing")),
+            Value::BulkString(Bytes::from("b")),
+            Value::BulkString(Bytes::from("a")),
         ];
-        handle_command(&db, &cmd_set1).await;
+        let resp = handle_command(&db, &mut ConnectionState::new(), &cmd_exists).await;
+        assert_eq!(resp, Some(CommandResult::Value(Value::Integer(3))));
+    }
 
-        let cmd_set2 = vec![
-            Value::BulkString(Bytes::from("SET")),
+    #[tokio::test]
+    async fn test_type() {
+        let db = new_db();
+        let cmd_set = vec![
+            Value::BulkString(Bytes::from("SET")),
+            Value::BulkString(Bytes::from("str")),
+            Value::BulkString(Bytes::from("value")),
+        ];
+        handle_command(&db, &mut ConnectionState::new(), &cmd_set).await;
+        db.shard("list").write().await.insert("list".to_string(), DbValue::new_list(vec![Bytes::from("a")]));
+
+        for (key, expected) in [("str", "string"), ("list", "list"), ("missing", "none")] {
+            let cmd = vec![
+                Value::BulkString(Bytes::from("TYPE")),
+                Value::BulkString(Bytes::from(key)),
+            ];
+            let resp = handle_command(&db, &mut ConnectionState::new(), &cmd).await;
+            assert_eq!(resp, Some(CommandResult::Value(Value::SimpleString(expected.to_string()))));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_keys() {
+        let db = new_db();
+        // Set some keys
+        let cmd_set1 = vec![
+            Value::BulkString(Bytes::from("SET")),
+            Value::BulkString(Bytes::from("key1")),
+            Value::BulkString(Bytes::from("value1")),
+        ];
+        handle_command(&db, &mut ConnectionState::new(), &cmd_set1).await;
+
+        let cmd_set2 = vec![
+            Value::BulkString(Bytes::from("SET")),
             Value::BulkString(Bytes::from("key2")),
             Value::BulkString(Bytes::from("value2")),
         ];
-        handle_command(&db, &cmd_set2).await;
+        handle_command(&db, &mut ConnectionState::new(), &cmd_set2).await;
 
         let cmd_keys = vec![
             Value::BulkString(Bytes::from("KEYS")),
             Value::BulkString(Bytes::from("*")),
         ];
-        let resp = handle_command(&db, &cmd_keys).await;
+        let resp = handle_command(&db, &mut ConnectionState::new(), &cmd_keys).await;
         match resp {
             Some(CommandResult::Value(Value::Array(arr))) => {
                 assert_eq!(arr.len(), 2);
@@ -405,4 +1640,1499 @@ mod tests {
             _ => panic!("Expected array"),
         }
     }
+
+    #[tokio::test]
+    async fn test_expireat_far_future() {
+        let db = new_db();
+        let bulk = |s: String| Value::BulkString(Bytes::from(s));
+        let cmd_ttl = vec![bulk("TTL".into()), bulk("key".into())];
+        let cmd_set = vec![bulk("SET".into()), bulk("key".into()), bulk("v".into())];
+
+        // 9999-12-31T23:59:59Z, then as far out as PEXPIREAT can be asked to go
+        for (name, at) in [("EXPIREAT", 253402300799), ("PEXPIREAT", i64::MAX)] {
+            handle_command(&db, &mut ConnectionState::new(), &cmd_set).await;
+            let cmd_expireat = vec![bulk(name.into()), bulk("key".into()), bulk(at.to_string())];
+            let resp = handle_command(&db, &mut ConnectionState::new(), &cmd_expireat).await;
+            assert_eq!(resp, Some(CommandResult::Value(Value::Integer(1))), "{}", name);
+            match handle_command(&db, &mut ConnectionState::new(), &cmd_ttl).await {
+                Some(CommandResult::Value(Value::Integer(ttl))) => {
+                    assert!(ttl > 0 && ttl <= MAX_EXPIRE.as_secs() as i64, "{} ttl {}", name, ttl)
+                }
+                other => panic!("Expected integer, got {:?}", other),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_expireat_in_the_past_deletes() {
+        let db = new_db();
+        let bulk = |s: String| Value::BulkString(Bytes::from(s));
+        let cmd_set = vec![bulk("SET".into()), bulk("key".into()), bulk("v".into())];
+        handle_command(&db, &mut ConnectionState::new(), &cmd_set).await;
+
+        let past = crate::db::unix_time_secs() - 10;
+        let cmd_expireat = vec![bulk("EXPIREAT".into()), bulk("key".into()), bulk(past.to_string())];
+        let resp = handle_command(&db, &mut ConnectionState::new(), &cmd_expireat).await;
+        assert_eq!(resp, Some(CommandResult::Value(Value::Integer(1))));
+        assert!(!db.shard("key").read().await.contains_key("key"));
+
+        let resp = handle_command(&db, &mut ConnectionState::new(), &cmd_expireat).await;
+        assert_eq!(resp, Some(CommandResult::Value(Value::Integer(0))));
+    }
+
+    #[tokio::test]
+    async fn test_dbsize() {
+        let clock = Arc::new(MockClock::new());
+        let db = new_db_with_clock(clock.clone());
+        for key in ["a", "b", "c"] {
+            insert(&db, key, DbValue::new_string(Bytes::from("v"))).await;
+        }
+        let mut expiring = DbValue::new_string(Bytes::from("v"));
+        expiring.expiry = Some(db.now() + Duration::from_secs(1));
+        insert(&db, "d", expiring).await;
+        let cmd_dbsize = vec![Value::BulkString(Bytes::from("DBSIZE"))];
+        let resp = handle_command(&db, &mut ConnectionState::new(), &cmd_dbsize).await;
+        assert_eq!(resp, Some(CommandResult::Value(Value::Integer(4))));
+
+        clock.advance(Duration::from_secs(2));
+        let resp = handle_command(&db, &mut ConnectionState::new(), &cmd_dbsize).await;
+        assert_eq!(resp, Some(CommandResult::Value(Value::Integer(3))));
+    }
+
+    #[tokio::test]
+    async fn test_flushdb() {
+        let db = new_db();
+        let cmd_mset = vec![
+            Value::BulkString(Bytes::from("MSET")),
+            Value::BulkString(Bytes::from("a")),
+            Value::BulkString(Bytes::from("1")),
+            Value::BulkString(Bytes::from("b")),
+            Value::BulkString(Bytes::from("2")),
+        ];
+        handle_command(&db, &mut ConnectionState::new(), &cmd_mset).await;
+        let (tx, _rx) = mpsc::unbounded_channel();
+        db.channels.write().await.insert(Bytes::from("news"), vec![Subscription::new(tx)]);
+
+        let cmd_flushdb = vec![Value::BulkString(Bytes::from("FLUSHDB"))];
+        let resp = handle_command(&db, &mut ConnectionState::new(), &cmd_flushdb).await;
+        assert_eq!(resp, Some(CommandResult::Value(Value::SimpleString("OK".to_string()))));
+
+        let cmd_dbsize = vec![Value::BulkString(Bytes::from("DBSIZE"))];
+        let resp = handle_command(&db, &mut ConnectionState::new(), &cmd_dbsize).await;
+        assert_eq!(resp, Some(CommandResult::Value(Value::Integer(0))));
+        assert!(db.channels.read().await.contains_key(&Bytes::from("news")));
+    }
+
+    #[tokio::test]
+    async fn test_keys_patterns() {
+        let clock = Arc::new(MockClock::new());
+        let db = new_db_with_clock(clock.clone());
+        for key in ["user:1", "user:2", "user:10", "session:1"] {
+            insert(&db, key, DbValue::new_string(Bytes::from("v"))).await;
+        }
+        let mut expiring = DbValue::new_string(Bytes::from("v"));
+        expiring.expiry = Some(db.now() + Duration::from_secs(1));
+        insert(&db, "user:3", expiring).await;
+        clock.advance(Duration::from_secs(2));
+
+        let keys = |pattern: &'static str| {
+            let db = db.clone();
+            async move {
+                let cmd_keys = vec![
+                    Value::BulkString(Bytes::from("KEYS")),
+                    Value::BulkString(Bytes::from(pattern)),
+                ];
+                match handle_command(&db, &mut ConnectionState::new(), &cmd_keys).await {
+                    Some(CommandResult::Value(Value::Array(arr))) => {
+                        let mut keys: Vec<String> = arr.iter().map(|v| match v {
+                            Value::BulkString(bs) => String::from_utf8(bs.to_vec()).unwrap(),
+                            _ => panic!("Expected bulk string"),
+                        }).collect();
+                        keys.sort();
+                        keys
+                    }
+                    _ => panic!("Expected array"),
+                }
+            }
+        };
+        assert_eq!(keys("*").await, vec!["session:1", "user:1", "user:10", "user:2"]);
+        assert_eq!(keys("user:*").await, vec!["user:1", "user:10", "user:2"]);
+        assert_eq!(keys("user:?").await, vec!["user:1", "user:2"]);
+        assert_eq!(keys("user:[13]*").await, vec!["user:1", "user:10"]);
+    }
+
+    #[tokio::test]
+    async fn test_client_pause_write() {
+        let db = new_db();
+        let cmd_pause = vec![
+            Value::BulkString(Bytes::from("CLIENT")),
+            Value::BulkString(Bytes::from("PAUSE")),
+            Value::BulkString(Bytes::from("10000")),
+            Value::BulkString(Bytes::from("WRITE")),
+        ];
+        let resp = handle_command(&db, &mut ConnectionState::new(), &cmd_pause).await;
+        assert_eq!(resp, Some(CommandResult::Value(Value::SimpleString("OK".to_string()))));
+
+        // SET is held while writes are paused
+        let db_clone = db.clone();
+        let set_task = tokio::spawn(async move {
+            let cmd_set = vec![
+                Value::BulkString(Bytes::from("SET")),
+                Value::BulkString(Bytes::from("key")),
+                Value::BulkString(Bytes::from("value")),
+            ];
+            wait_if_paused(&db_clone, &cmd_set).await;
+            handle_command(&db_clone, &mut ConnectionState::new(), &cmd_set).await
+        });
+
+        // GET still goes through
+        let cmd_get = vec![
+            Value::BulkString(Bytes::from("GET")),
+            Value::BulkString(Bytes::from("key")),
+        ];
+        tokio::time::timeout(Duration::from_millis(100), wait_if_paused(&db, &cmd_get))
+            .await
+            .expect("GET should not be paused");
+        let resp_get = handle_command(&db, &mut ConnectionState::new(), &cmd_get).await;
+        assert_eq!(resp_get, Some(CommandResult::Value(Value::Null)));
+
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!set_task.is_finished());
+
+        let cmd_unpause = vec![
+            Value::BulkString(Bytes::from("CLIENT")),
+            Value::BulkString(Bytes::from("UNPAUSE")),
+        ];
+        handle_command(&db, &mut ConnectionState::new(), &cmd_unpause).await;
+
+        let resp_set = tokio::time::timeout(Duration::from_secs(1), set_task)
+            .await
+            .expect("SET should resume after UNPAUSE")
+            .unwrap();
+        assert_eq!(resp_set, Some(CommandResult::Value(Value::SimpleString("OK".to_string()))));
+    }
+
+    #[tokio::test]
+    async fn test_client_pause_timeout_out_of_range() {
+        let db = new_db();
+        let cmd_pause = vec![
+            Value::BulkString(Bytes::from("CLIENT")),
+            Value::BulkString(Bytes::from("PAUSE")),
+            Value::BulkString(Bytes::from(i64::MAX.to_string())),
+        ];
+        let resp = handle_command(&db, &mut ConnectionState::new(), &cmd_pause).await;
+        assert_eq!(resp, Some(CommandResult::Value(Value::Error("ERR timeout is out of range".to_string()))));
+        assert!(db.pause.read().await.deadline.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_setnx() {
+        let db = new_db();
+        let cmd_first = vec![
+            Value::BulkString(Bytes::from("SETNX")),
+            Value::BulkString(Bytes::from("key")),
+            Value::BulkString(Bytes::from("first")),
+        ];
+        let resp = handle_command(&db, &mut ConnectionState::new(), &cmd_first).await;
+        assert_eq!(resp, Some(CommandResult::Value(Value::Integer(1))));
+
+        let cmd_second = vec![
+            Value::BulkString(Bytes::from("SETNX")),
+            Value::BulkString(Bytes::from("key")),
+            Value::BulkString(Bytes::from("second")),
+        ];
+        let resp = handle_command(&db, &mut ConnectionState::new(), &cmd_second).await;
+        assert_eq!(resp, Some(CommandResult::Value(Value::Integer(0))));
+
+        let cmd_get = vec![
+            Value::BulkString(Bytes::from("GET")),
+            Value::BulkString(Bytes::from("key")),
+        ];
+        let resp = handle_command(&db, &mut ConnectionState::new(), &cmd_get).await;
+        assert_eq!(resp, Some(CommandResult::Value(Value::BulkString(Bytes::from("first")))));
+        assert!(db.shard("key").read().await.get("key").unwrap().expiry.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_getset() {
+        let db = new_db();
+        let getset = |value: &str| vec![
+            Value::BulkString(Bytes::from("GETSET")),
+            Value::BulkString(Bytes::from("key")),
+            Value::BulkString(Bytes::from(value.to_string())),
+        ];
+        let resp = handle_command(&db, &mut ConnectionState::new(), &getset("one")).await;
+        assert_eq!(resp, Some(CommandResult::Value(Value::Null)));
+
+        let cmd_expire = vec![
+            Value::BulkString(Bytes::from("EXPIRE")),
+            Value::BulkString(Bytes::from("key")),
+            Value::BulkString(Bytes::from("100")),
+        ];
+        handle_command(&db, &mut ConnectionState::new(), &cmd_expire).await;
+
+        let resp = handle_command(&db, &mut ConnectionState::new(), &getset("two")).await;
+        assert_eq!(resp, Some(CommandResult::Value(Value::BulkString(Bytes::from("one")))));
+        let cmd_get = vec![
+            Value::BulkString(Bytes::from("GET")),
+            Value::BulkString(Bytes::from("key")),
+        ];
+        let resp = handle_command(&db, &mut ConnectionState::new(), &cmd_get).await;
+        assert_eq!(resp, Some(CommandResult::Value(Value::BulkString(Bytes::from("two")))));
+        assert!(db.shard("key").read().await.get("key").unwrap().expiry.is_none());
+
+        let cmd_lpush = vec![
+            Value::BulkString(Bytes::from("LPUSH")),
+            Value::BulkString(Bytes::from("list")),
+            Value::BulkString(Bytes::from("x")),
+        ];
+        handle_command(&db, &mut ConnectionState::new(), &cmd_lpush).await;
+        let cmd_getset_list = vec![
+            Value::BulkString(Bytes::from("GETSET")),
+            Value::BulkString(Bytes::from("list")),
+            Value::BulkString(Bytes::from("y")),
+        ];
+        let resp = handle_command(&db, &mut ConnectionState::new(), &cmd_getset_list).await;
+        assert_eq!(resp, Some(CommandResult::Value(wrongtype())));
+    }
+
+    #[tokio::test]
+    async fn test_getdel() {
+        let db = new_db();
+        let cmd_set = vec![
+            Value::BulkString(Bytes::from("SET")),
+            Value::BulkString(Bytes::from("token")),
+            Value::BulkString(Bytes::from("secret")),
+        ];
+        handle_command(&db, &mut ConnectionState::new(), &cmd_set).await;
+
+        let cmd_getdel = vec![
+            Value::BulkString(Bytes::from("GETDEL")),
+            Value::BulkString(Bytes::from("token")),
+        ];
+        let resp = handle_command(&db, &mut ConnectionState::new(), &cmd_getdel).await;
+        assert_eq!(resp, Some(CommandResult::Value(Value::BulkString(Bytes::from("secret")))));
+        let resp = handle_command(&db, &mut ConnectionState::new(), &cmd_getdel).await;
+        assert_eq!(resp, Some(CommandResult::Value(Value::Null)));
+
+        let cmd_get = vec![
+            Value::BulkString(Bytes::from("GET")),
+            Value::BulkString(Bytes::from("token")),
+        ];
+        let resp = handle_command(&db, &mut ConnectionState::new(), &cmd_get).await;
+        assert_eq!(resp, Some(CommandResult::Value(Value::Null)));
+
+        let cmd_lpush = vec![
+            Value::BulkString(Bytes::from("LPUSH")),
+            Value::BulkString(Bytes::from("list")),
+            Value::BulkString(Bytes::from("x")),
+        ];
+        handle_command(&db, &mut ConnectionState::new(), &cmd_lpush).await;
+        let cmd_getdel_list = vec![
+            Value::BulkString(Bytes::from("GETDEL")),
+            Value::BulkString(Bytes::from("list")),
+        ];
+        let resp = handle_command(&db, &mut ConnectionState::new(), &cmd_getdel_list).await;
+        assert_eq!(resp, Some(CommandResult::Value(wrongtype())));
+        assert!(db.shard("list").read().await.contains_key("list"));
+    }
+
+    #[tokio::test]
+    async fn test_mset_mget() {
+        let db = new_db();
+        let cmd_mset = vec![
+            Value::BulkString(Bytes::from("MSET")),
+            Value::BulkString(Bytes::from("a")),
+            Value::BulkString(Bytes::from("1")),
+            Value::BulkString(Bytes::from("b")),
+            Value::BulkString(Bytes::from("2")),
+        ];
+        let resp = handle_command(&db, &mut ConnectionState::new(), &cmd_mset).await;
+        assert_eq!(resp, Some(CommandResult::Value(Value::SimpleString("OK".to_string()))));
+
+        let cmd_lpush = vec![
+            Value::BulkString(Bytes::from("LPUSH")),
+            Value::BulkString(Bytes::from("list")),
+            Value::BulkString(Bytes::from("x")),
+        ];
+        handle_command(&db, &mut ConnectionState::new(), &cmd_lpush).await;
+
+        let cmd_mget = vec![
+            Value::BulkString(Bytes::from("MGET")),
+            Value::BulkString(Bytes::from("a")),
+            Value::BulkString(Bytes::from("missing")),
+            Value::BulkString(Bytes::from("list")),
+            Value::BulkString(Bytes::from("b")),
+        ];
+        let resp = handle_command(&db, &mut ConnectionState::new(), &cmd_mget).await;
+        assert_eq!(resp, Some(CommandResult::Value(Value::Array(vec![
+            Value::BulkString(Bytes::from("1")),
+            Value::Null,
+            Value::Null,
+            Value::BulkString(Bytes::from("2")),
+        ]))));
+
+        let cmd_odd = vec![
+            Value::BulkString(Bytes::from("MSET")),
+            Value::BulkString(Bytes::from("a")),
+            Value::BulkString(Bytes::from("1")),
+            Value::BulkString(Bytes::from("b")),
+        ];
+        let resp = handle_command(&db, &mut ConnectionState::new(), &cmd_odd).await;
+        assert_eq!(resp, Some(CommandResult::Value(wrong_arity("mset"))));
+    }
+
+    #[tokio::test]
+    async fn test_client_setname_updates_connection_state() {
+        let db = new_db();
+        let mut state = ConnectionState::new();
+        let cmd_setname = vec![
+            Value::BulkString(Bytes::from("CLIENT")),
+            Value::BulkString(Bytes::from("SETNAME")),
+            Value::BulkString(Bytes::from("worker-1")),
+        ];
+        let resp = handle_command(&db, &mut state, &cmd_setname).await;
+        assert_eq!(resp, Some(CommandResult::Value(Value::SimpleString("OK".to_string()))));
+        assert_eq!(state.name, Some(Bytes::from("worker-1")));
+
+        let cmd_getname = vec![
+            Value::BulkString(Bytes::from("CLIENT")),
+            Value::BulkString(Bytes::from("GETNAME")),
+        ];
+        let resp = handle_command(&db, &mut state, &cmd_getname).await;
+        assert_eq!(resp, Some(CommandResult::Value(Value::BulkString(Bytes::from("worker-1")))));
+
+        // Another connection has its own state
+        let mut other = ConnectionState::new();
+        assert_ne!(other.id, state.id);
+        let resp = handle_command(&db, &mut other, &cmd_getname).await;
+        assert_eq!(resp, Some(CommandResult::Value(Value::Null)));
+
+        let cmd_bad_name = vec![
+            Value::BulkString(Bytes::from("CLIENT")),
+            Value::BulkString(Bytes::from("SETNAME")),
+            Value::BulkString(Bytes::from("two words")),
+        ];
+        let resp = handle_command(&db, &mut state, &cmd_bad_name).await;
+        assert!(matches!(resp, Some(CommandResult::Value(Value::Error(_)))));
+        assert_eq!(state.name, Some(Bytes::from("worker-1")));
+
+        let cmd_id = vec![
+            Value::BulkString(Bytes::from("CLIENT")),
+            Value::BulkString(Bytes::from("ID")),
+        ];
+        let resp = handle_command(&db, &mut state, &cmd_id).await;
+        assert_eq!(resp, Some(CommandResult::Value(Value::Integer(state.id as i64))));
+    }
+
+    #[tokio::test]
+    async fn test_reset_clears_connection_state() {
+        let db = new_db();
+        let mut state = ConnectionState::new();
+        state.name = Some(Bytes::from("worker-1"));
+        state.db = 3;
+        state.queued = Some(vec![vec![Value::BulkString(Bytes::from("PING"))]]);
+        let id = state.id;
+
+        let cmd_reset = vec![Value::BulkString(Bytes::from("RESET"))];
+        let resp = handle_command(&db, &mut state, &cmd_reset).await;
+        assert_eq!(resp, Some(CommandResult::Value(Value::SimpleString("RESET".to_string()))));
+        assert_eq!(state, ConnectionState { id, ..ConnectionState::new() });
+    }
+
+    #[tokio::test]
+    async fn test_client_unknown_subcommand() {
+        let db = new_db();
+        let cmd = vec![
+            Value::BulkString(Bytes::from("CLIENT")),
+            Value::BulkString(Bytes::from("Bogus")),
+            Value::BulkString(Bytes::from("1")),
+        ];
+        let resp = handle_command(&db, &mut ConnectionState::new(), &cmd).await;
+        assert_eq!(resp, Some(CommandResult::Value(Value::Error(
+            "ERR Unknown subcommand or wrong number of arguments for 'Bogus'. Try CLIENT HELP.".to_string()
+        ))));
+    }
+
+    #[test]
+    fn test_error_helpers() {
+        assert_eq!(wrongtype(), Value::Error("WRONGTYPE Operation against a key holding the wrong kind of value".to_string()));
+        assert_eq!(not_integer(), Value::Error("ERR value is not an integer or out of range".to_string()));
+        assert_eq!(syntax_error(), Value::Error("ERR syntax error".to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_scan_returns_keys_present_throughout() {
+        let db = new_db();
+        let bulk = |s: String| Value::BulkString(Bytes::from(s));
+        for i in 0..200 {
+            let cmd_set = vec![bulk("SET".into()), bulk(format!("key{}", i)), bulk("v".into())];
+            handle_command(&db, &mut ConnectionState::new(), &cmd_set).await;
+        }
+
+        let mut seen = std::collections::HashSet::new();
+        let mut cursor = "0".to_string();
+        let mut round = 0;
+        loop {
+            let cmd_scan = vec![bulk("SCAN".into()), bulk(cursor.clone()), bulk("COUNT".into()), bulk("7".into())];
+            let (next, keys) = match handle_command(&db, &mut ConnectionState::new(), &cmd_scan).await {
+                Some(CommandResult::Value(Value::Array(reply))) => match &reply[..] {
+                    [Value::BulkString(next), Value::Array(keys)] => (next.clone(), keys.clone()),
+                    _ => panic!("Expected [cursor, keys]"),
+                },
+                _ => panic!("Expected array"),
+            };
+            for key in keys {
+                if let Value::BulkString(bs) = key {
+                    seen.insert(String::from_utf8(bs.to_vec()).unwrap());
+                }
+            }
+
+            // Churn the keyspace between calls: drop some originals, add new keys
+            let cmd_del = vec![bulk("DEL".into()), bulk(format!("key{}", round * 3))];
+            handle_command(&db, &mut ConnectionState::new(), &cmd_del).await;
+            let cmd_set = vec![bulk("SET".into()), bulk(format!("new{}", round)), bulk("v".into())];
+            handle_command(&db, &mut ConnectionState::new(), &cmd_set).await;
+            round += 1;
+
+            cursor = String::from_utf8(next.to_vec()).unwrap();
+            if cursor == "0" {
+                break;
+            }
+        }
+
+        for i in 0..200 {
+            let deleted = i % 3 == 0 && i / 3 < round;
+            if !deleted {
+                assert!(seen.contains(&format!("key{}", i)), "key{} was missed", i);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_scan_caps_huge_count() {
+        let db = new_db();
+        for i in 0..SCAN_MAX_COUNT * 3 {
+            insert(&db, &format!("key{}", i), DbValue::new_string(Bytes::from("v"))).await;
+        }
+
+        let bulk = |s: &str| Value::BulkString(Bytes::from(s.to_string()));
+        let cmd_scan = vec![bulk("SCAN"), bulk("0"), bulk("COUNT"), bulk("999999999")];
+        match handle_command(&db, &mut ConnectionState::new(), &cmd_scan).await {
+            Some(CommandResult::Value(Value::Array(reply))) => match &reply[..] {
+                [Value::BulkString(next), Value::Array(keys)] => {
+                    assert_ne!(next, &Bytes::from("0"));
+                    assert!(!keys.is_empty());
+                    // Keys sharing a hash may spill just past the cap
+                    assert!(keys.len() < SCAN_MAX_COUNT + 10, "{} keys", keys.len());
+                }
+                _ => panic!("Expected [cursor, keys]"),
+            },
+            _ => panic!("Expected array"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_scan_skips_expired_keys() {
+        let clock = Arc::new(MockClock::new());
+        let db = new_db_with_clock(clock.clone());
+        for i in 0..50 {
+            let mut val = DbValue::new_string(Bytes::from("v"));
+            if i % 2 == 0 {
+                val.expiry = Some(db.now() + Duration::from_secs(1));
+            }
+            insert(&db, &format!("key{}", i), val).await;
+        }
+        clock.advance(Duration::from_secs(2));
+
+        let mut seen = Vec::new();
+        let mut cursor = "0".to_string();
+        loop {
+            let cmd = vec![
+                Value::BulkString(Bytes::from("SCAN")),
+                Value::BulkString(Bytes::from(cursor)),
+                Value::BulkString(Bytes::from("COUNT")),
+                Value::BulkString(Bytes::from("7")),
+            ];
+            let (next, keys) = match handle_command(&db, &mut ConnectionState::new(), &cmd).await {
+                Some(CommandResult::Value(Value::Array(mut reply))) => match (reply.remove(0), reply.remove(0)) {
+                    (Value::BulkString(next), Value::Array(keys)) => (next, keys),
+                    other => panic!("Unexpected SCAN reply {:?}", other),
+                },
+                other => panic!("Unexpected SCAN reply {:?}", other),
+            };
+            seen.extend(keys.into_iter().map(|k| match k {
+                Value::BulkString(k) => String::from_utf8(k.to_vec()).unwrap(),
+                other => panic!("Unexpected key {:?}", other),
+            }));
+            cursor = String::from_utf8(next.to_vec()).unwrap();
+            if cursor == "0" {
+                break;
+            }
+        }
+
+        seen.sort();
+        let mut live: Vec<String> = (0..50).filter(|i| i % 2 == 1).map(|i| format!("key{}", i)).collect();
+        live.sort();
+        assert_eq!(seen, live);
+    }
+
+    #[tokio::test]
+    async fn test_set_ex_with_mock_clock() {
+        let clock = Arc::new(MockClock::new());
+        let db = new_db_with_clock(clock.clone());
+        let cmd_set = vec![
+            Value::BulkString(Bytes::from("SET")),
+            Value::BulkString(Bytes::from("key")),
+            Value::BulkString(Bytes::from("value")),
+            Value::BulkString(Bytes::from("EX")),
+            Value::BulkString(Bytes::from("100")),
+        ];
+        handle_command(&db, &mut ConnectionState::new(), &cmd_set).await;
+
+        let cmd_exists = vec![
+            Value::BulkString(Bytes::from("EXISTS")),
+            Value::BulkString(Bytes::from("key")),
+        ];
+        let resp = handle_command(&db, &mut ConnectionState::new(), &cmd_exists).await;
+        assert_eq!(resp, Some(CommandResult::Value(Value::Integer(1))));
+
+        // Jump past the TTL without sleeping
+        clock.advance(Duration::from_secs(101));
+        let resp = handle_command(&db, &mut ConnectionState::new(), &cmd_exists).await;
+        assert_eq!(resp, Some(CommandResult::Value(Value::Integer(0))));
+    }
+
+    #[tokio::test]
+    async fn test_set_px() {
+        let clock = Arc::new(MockClock::new());
+        let db = new_db_with_clock(clock.clone());
+        let cmd_set = vec![
+            Value::BulkString(Bytes::from("SET")),
+            Value::BulkString(Bytes::from("key")),
+            Value::BulkString(Bytes::from("value")),
+            Value::BulkString(Bytes::from("px")),
+            Value::BulkString(Bytes::from("100")),
+        ];
+        let resp = handle_command(&db, &mut ConnectionState::new(), &cmd_set).await;
+        assert_eq!(resp, Some(CommandResult::Value(Value::SimpleString("OK".to_string()))));
+
+        let cmd_exists = vec![
+            Value::BulkString(Bytes::from("EXISTS")),
+            Value::BulkString(Bytes::from("key")),
+        ];
+        clock.advance(Duration::from_millis(99));
+        let resp = handle_command(&db, &mut ConnectionState::new(), &cmd_exists).await;
+        assert_eq!(resp, Some(CommandResult::Value(Value::Integer(1))));
+
+        clock.advance(Duration::from_millis(2));
+        let resp = handle_command(&db, &mut ConnectionState::new(), &cmd_exists).await;
+        assert_eq!(resp, Some(CommandResult::Value(Value::Integer(0))));
+    }
+
+    #[tokio::test]
+    async fn test_set_rejects_ex_with_px() {
+        let db = new_db();
+        let cmd_set = vec![
+            Value::BulkString(Bytes::from("SET")),
+            Value::BulkString(Bytes::from("key")),
+            Value::BulkString(Bytes::from("value")),
+            Value::BulkString(Bytes::from("EX")),
+            Value::BulkString(Bytes::from("10")),
+            Value::BulkString(Bytes::from("PX")),
+            Value::BulkString(Bytes::from("100")),
+        ];
+        let resp = handle_command(&db, &mut ConnectionState::new(), &cmd_set).await;
+        assert_eq!(resp, Some(CommandResult::Value(syntax_error())));
+
+        let cmd_exists = vec![
+            Value::BulkString(Bytes::from("EXISTS")),
+            Value::BulkString(Bytes::from("key")),
+        ];
+        let resp = handle_command(&db, &mut ConnectionState::new(), &cmd_exists).await;
+        assert_eq!(resp, Some(CommandResult::Value(Value::Integer(0))));
+    }
+
+    #[tokio::test]
+    async fn test_set_nx_xx() {
+        let db = new_db();
+        let set = |value: &'static str, flags: &[&'static str]| {
+            let mut cmd = vec![
+                Value::BulkString(Bytes::from("SET")),
+                Value::BulkString(Bytes::from("key")),
+                Value::BulkString(Bytes::from(value)),
+            ];
+            cmd.extend(flags.iter().map(|flag| Value::BulkString(Bytes::from(*flag))));
+            cmd
+        };
+        let cmd_get = vec![
+            Value::BulkString(Bytes::from("GET")),
+            Value::BulkString(Bytes::from("key")),
+        ];
+        let ok = Some(CommandResult::Value(Value::SimpleString("OK".to_string())));
+
+        // XX on a missing key does nothing
+        let resp = handle_command(&db, &mut ConnectionState::new(), &set("a", &["XX"])).await;
+        assert_eq!(resp, Some(CommandResult::Value(Value::Null)));
+        let resp = handle_command(&db, &mut ConnectionState::new(), &cmd_get).await;
+        assert_eq!(resp, Some(CommandResult::Value(Value::Null)));
+
+        let resp = handle_command(&db, &mut ConnectionState::new(), &set("b", &["NX", "EX", "10"])).await;
+        assert_eq!(resp, ok);
+
+        // NX on an existing key does nothing
+        let resp = handle_command(&db, &mut ConnectionState::new(), &set("c", &["NX"])).await;
+        assert_eq!(resp, Some(CommandResult::Value(Value::Null)));
+        let resp = handle_command(&db, &mut ConnectionState::new(), &cmd_get).await;
+        assert_eq!(resp, Some(CommandResult::Value(Value::BulkString(Bytes::from("b")))));
+
+        let resp = handle_command(&db, &mut ConnectionState::new(), &set("d", &["XX"])).await;
+        assert_eq!(resp, ok);
+        let resp = handle_command(&db, &mut ConnectionState::new(), &cmd_get).await;
+        assert_eq!(resp, Some(CommandResult::Value(Value::BulkString(Bytes::from("d")))));
+
+        let resp = handle_command(&db, &mut ConnectionState::new(), &set("e", &["NX", "XX"])).await;
+        assert_eq!(resp, Some(CommandResult::Value(syntax_error())));
+    }
+
+    #[tokio::test]
+    async fn test_ssubscribe() {
+        let db = new_db();
+        let cmd = vec![
+            Value::BulkString(Bytes::from("SSUBSCRIBE")),
+            Value::BulkString(Bytes::from("news")),
+        ];
+        let resp = handle_command(&db, &mut ConnectionState::new(), &cmd).await;
+        assert_eq!(resp, Some(CommandResult::SSubscribe(vec![Bytes::from("news")])));
+    }
+
+    #[tokio::test]
+    async fn test_spublish() {
+        let db = new_db();
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        db.shard_channels.write().await.insert(Bytes::from("news"), vec![Subscription::new(tx)]);
+
+        let cmd_spublish = vec![
+            Value::BulkString(Bytes::from("SPUBLISH")),
+            Value::BulkString(Bytes::from("news")),
+            Value::BulkString(Bytes::from("hello")),
+        ];
+        let resp = handle_command(&db, &mut ConnectionState::new(), &cmd_spublish).await;
+        assert_eq!(resp, Some(CommandResult::Value(Value::Integer(1))));
+        assert_eq!(rx.recv().await, Some(PubSubMessage {
+            sharded: true,
+            channel: Bytes::from("news"),
+            payload: Bytes::from("hello"),
+        }));
+
+        // Sharded subscribers don't see regular PUBLISH on the same name
+        let cmd_publish = vec![
+            Value::BulkString(Bytes::from("PUBLISH")),
+            Value::BulkString(Bytes::from("news")),
+            Value::BulkString(Bytes::from("hello")),
+        ];
+        let resp = handle_command(&db, &mut ConnectionState::new(), &cmd_publish).await;
+        assert_eq!(resp, Some(CommandResult::Value(Value::Integer(0))));
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_binary_channel_names() {
+        let db = new_db();
+        let channel = Bytes::from_static(b"ne\0ws\xff");
+        let cmd = vec![
+            Value::BulkString(Bytes::from("SUBSCRIBE")),
+            Value::BulkString(channel.clone()),
+        ];
+        let resp = handle_command(&db, &mut ConnectionState::new(), &cmd).await;
+        assert_eq!(resp, Some(CommandResult::Subscribe(vec![channel.clone()])));
+
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        db.channels.write().await.insert(channel.clone(), vec![Subscription::new(tx)]);
+        let cmd_publish = vec![
+            Value::BulkString(Bytes::from("PUBLISH")),
+            Value::BulkString(channel.clone()),
+            Value::BulkString(Bytes::from_static(b"\0payload")),
+        ];
+        let resp = handle_command(&db, &mut ConnectionState::new(), &cmd_publish).await;
+        assert_eq!(resp, Some(CommandResult::Value(Value::Integer(1))));
+        assert_eq!(rx.recv().await, Some(PubSubMessage {
+            sharded: false,
+            channel,
+            payload: Bytes::from_static(b"\0payload"),
+        }));
+    }
+
+    #[tokio::test]
+    async fn test_slow_subscriber_is_disconnected() {
+        let db = new_db_with_config(&ServerConfig {
+            pubsub_output_limit: OutputBufferLimit { hard: 16, soft: 0, soft_duration: Duration::ZERO },
+            ..Default::default()
+        });
+        let (tx, _rx) = mpsc::unbounded_channel();
+        let subscription = Subscription::new(tx);
+        let buffer = subscription.buffer.clone();
+        db.channels.write().await.insert(Bytes::from("news"), vec![subscription]);
+        let cmd_publish = vec![
+            Value::BulkString(Bytes::from("PUBLISH")),
+            Value::BulkString(Bytes::from("news")),
+            Value::BulkString(Bytes::from("0123456789")),
+        ];
+
+        // Nothing drains the queue, so the second message goes over the limit
+        let resp = handle_command(&db, &mut ConnectionState::new(), &cmd_publish).await;
+        assert_eq!(resp, Some(CommandResult::Value(Value::Integer(1))));
+        let resp = handle_command(&db, &mut ConnectionState::new(), &cmd_publish).await;
+        assert_eq!(resp, Some(CommandResult::Value(Value::Integer(0))));
+        assert!(!db.channels.read().await.contains_key(&Bytes::from("news")));
+        tokio::time::timeout(Duration::from_secs(1), buffer.closed())
+            .await
+            .expect("subscriber should have been told to disconnect");
+    }
+
+    #[tokio::test]
+    async fn test_lpush_rpush() {
+        let db = new_db();
+        let push = |name: &'static str, values: &[&'static str]| {
+            let mut cmd = vec![
+                Value::BulkString(Bytes::from(name)),
+                Value::BulkString(Bytes::from("list")),
+            ];
+            cmd.extend(values.iter().map(|v| Value::BulkString(Bytes::from(*v))));
+            cmd
+        };
+
+        let resp = handle_command(&db, &mut ConnectionState::new(), &push("LPUSH", &["b", "a"])).await;
+        assert_eq!(resp, Some(CommandResult::Value(Value::Integer(2))));
+        let resp = handle_command(&db, &mut ConnectionState::new(), &push("RPUSH", &["c", "d"])).await;
+        assert_eq!(resp, Some(CommandResult::Value(Value::Integer(4))));
+        let resp = handle_command(&db, &mut ConnectionState::new(), &push("LPUSH", &["y", "z"])).await;
+        assert_eq!(resp, Some(CommandResult::Value(Value::Integer(6))));
+
+        let expected: Vec<Bytes> = ["z", "y", "a", "b", "c", "d"].into_iter().map(Bytes::from).collect();
+        assert_eq!(db.shard("list").read().await.get("list").unwrap().data, DataType::List(expected));
+
+        let cmd_set = vec![
+            Value::BulkString(Bytes::from("SET")),
+            Value::BulkString(Bytes::from("str")),
+            Value::BulkString(Bytes::from("value")),
+        ];
+        handle_command(&db, &mut ConnectionState::new(), &cmd_set).await;
+        let cmd = vec![
+            Value::BulkString(Bytes::from("RPUSH")),
+            Value::BulkString(Bytes::from("str")),
+            Value::BulkString(Bytes::from("x")),
+        ];
+        let resp = handle_command(&db, &mut ConnectionState::new(), &cmd).await;
+        assert_eq!(resp, Some(CommandResult::Value(wrongtype())));
+    }
+
+    #[tokio::test]
+    async fn test_llen() {
+        let db = new_db();
+        let list: Vec<Bytes> = ["a", "b", "c"].into_iter().map(Bytes::from).collect();
+        db.shard("list").write().await.insert("list".to_string(), DbValue::new_list(list));
+        let cmd_set = vec![
+            Value::BulkString(Bytes::from("SET")),
+            Value::BulkString(Bytes::from("str")),
+            Value::BulkString(Bytes::from("value")),
+        ];
+        handle_command(&db, &mut ConnectionState::new(), &cmd_set).await;
+
+        for (key, expected) in [("list", Value::Integer(3)), ("missing", Value::Integer(0)), ("str", wrongtype())] {
+            let cmd = vec![
+                Value::BulkString(Bytes::from("LLEN")),
+                Value::BulkString(Bytes::from(key)),
+            ];
+            let resp = handle_command(&db, &mut ConnectionState::new(), &cmd).await;
+            assert_eq!(resp, Some(CommandResult::Value(expected)));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_lindex() {
+        let db = new_db();
+        let list: Vec<Bytes> = ["a", "b", "c"].into_iter().map(Bytes::from).collect();
+        db.shard("list").write().await.insert("list".to_string(), DbValue::new_list(list));
+        let lindex = |key: &'static str, index: &'static str| vec![
+            Value::BulkString(Bytes::from("LINDEX")),
+            Value::BulkString(Bytes::from(key)),
+            Value::BulkString(Bytes::from(index)),
+        ];
+
+        let resp = handle_command(&db, &mut ConnectionState::new(), &lindex("list", "0")).await;
+        assert_eq!(resp, Some(CommandResult::Value(Value::BulkString(Bytes::from("a")))));
+        let resp = handle_command(&db, &mut ConnectionState::new(), &lindex("list", "-1")).await;
+        assert_eq!(resp, Some(CommandResult::Value(Value::BulkString(Bytes::from("c")))));
+        let resp = handle_command(&db, &mut ConnectionState::new(), &lindex("list", "-3")).await;
+        assert_eq!(resp, Some(CommandResult::Value(Value::BulkString(Bytes::from("a")))));
+        for index in ["3", "-4"] {
+            let resp = handle_command(&db, &mut ConnectionState::new(), &lindex("list", index)).await;
+            assert_eq!(resp, Some(CommandResult::Value(Value::Null)));
+        }
+        let resp = handle_command(&db, &mut ConnectionState::new(), &lindex("missing", "0")).await;
+        assert_eq!(resp, Some(CommandResult::Value(Value::Null)));
+    }
+
+    #[tokio::test]
+    async fn test_lpop_rpop() {
+        let db = new_db();
+        let list: Vec<Bytes> = ["a", "b", "c"].into_iter().map(Bytes::from).collect();
+        db.shard("list").write().await.insert("list".to_string(), DbValue::new_list(list));
+        let pop = |name: &'static str, count: Option<&'static str>| {
+            let mut cmd = vec![
+                Value::BulkString(Bytes::from(name)),
+                Value::BulkString(Bytes::from("list")),
+            ];
+            cmd.extend(count.map(|c| Value::BulkString(Bytes::from(c))));
+            cmd
+        };
+
+        let resp = handle_command(&db, &mut ConnectionState::new(), &pop("LPOP", None)).await;
+        assert_eq!(resp, Some(CommandResult::Value(Value::BulkString(Bytes::from("a")))));
+        let resp = handle_command(&db, &mut ConnectionState::new(), &pop("RPOP", None)).await;
+        assert_eq!(resp, Some(CommandResult::Value(Value::BulkString(Bytes::from("c")))));
+
+        let resp = handle_command(&db, &mut ConnectionState::new(), &pop("RPOP", Some("-1"))).await;
+        assert_eq!(resp, Some(CommandResult::Value(Value::Error("ERR value is out of range, must be positive".to_string()))));
+        let resp = handle_command(&db, &mut ConnectionState::new(), &pop("RPOP", Some("0"))).await;
+        assert_eq!(resp, Some(CommandResult::Value(Value::Array(vec![]))));
+
+        // A count larger than the list pops everything and removes the key
+        let resp = handle_command(&db, &mut ConnectionState::new(), &pop("LPOP", Some("10"))).await;
+        assert_eq!(resp, Some(CommandResult::Value(Value::Array(vec![Value::BulkString(Bytes::from("b"))]))));
+        let cmd_type = vec![
+            Value::BulkString(Bytes::from("TYPE")),
+            Value::BulkString(Bytes::from("list")),
+        ];
+        let resp = handle_command(&db, &mut ConnectionState::new(), &cmd_type).await;
+        assert_eq!(resp, Some(CommandResult::Value(Value::SimpleString("none".to_string()))));
+
+        let resp = handle_command(&db, &mut ConnectionState::new(), &pop("LPOP", None)).await;
+        assert_eq!(resp, Some(CommandResult::Value(Value::Null)));
+        let resp = handle_command(&db, &mut ConnectionState::new(), &pop("LPOP", Some("2"))).await;
+        assert_eq!(resp, Some(CommandResult::Value(Value::Null)));
+    }
+
+    #[tokio::test]
+    async fn test_lmpop() {
+        let db = new_db();
+        insert(&db, "empty", DbValue::new_list(vec![])).await;
+        insert(&db, "list", DbValue::new_list(vec![
+            Bytes::from("a"), Bytes::from("b"), Bytes::from("c"),
+        ])).await;
+
+        let cmd = vec![
+            Value::BulkString(Bytes::from("LMPOP")),
+            Value::BulkString(Bytes::from("3")),
+            Value::BulkString(Bytes::from("missing")),
+            Value::BulkString(Bytes::from("empty")),
+            Value::BulkString(Bytes::from("list")),
+            Value::BulkString(Bytes::from("RIGHT")),
+            Value::BulkString(Bytes::from("COUNT")),
+            Value::BulkString(Bytes::from("2")),
+        ];
+        let resp = handle_command(&db, &mut ConnectionState::new(), &cmd).await;
+        assert_eq!(resp, Some(CommandResult::Value(Value::Array(vec![
+            Value::BulkString(Bytes::from("list")),
+            Value::Array(vec![
+                Value::BulkString(Bytes::from("c")),
+                Value::BulkString(Bytes::from("b")),
+            ]),
+        ]))));
+
+        // Popping the last element removes the key
+        let cmd = vec![
+            Value::BulkString(Bytes::from("LMPOP")),
+            Value::BulkString(Bytes::from("1")),
+            Value::BulkString(Bytes::from("list")),
+            Value::BulkString(Bytes::from("LEFT")),
+        ];
+        handle_command(&db, &mut ConnectionState::new(), &cmd).await;
+        let cmd = vec![
+            Value::BulkString(Bytes::from("EXISTS")),
+            Value::BulkString(Bytes::from("list")),
+        ];
+        let resp = handle_command(&db, &mut ConnectionState::new(), &cmd).await;
+        assert_eq!(resp, Some(CommandResult::Value(Value::Integer(0))));
+    }
+
+    #[tokio::test]
+    async fn test_lmpop_all_empty() {
+        let db = new_db();
+        let cmd = vec![
+            Value::BulkString(Bytes::from("LMPOP")),
+            Value::BulkString(Bytes::from("2")),
+            Value::BulkString(Bytes::from("a")),
+            Value::BulkString(Bytes::from("b")),
+            Value::BulkString(Bytes::from("LEFT")),
+        ];
+        let resp = handle_command(&db, &mut ConnectionState::new(), &cmd).await;
+        assert_eq!(resp, Some(CommandResult::Value(Value::Null)));
+    }
+
+    #[test]
+    fn test_subscribed_context_error() {
+        let cmd_get = vec![
+            Value::BulkString(Bytes::from("GET")),
+            Value::BulkString(Bytes::from("key")),
+        ];
+        assert_eq!(subscribed_context_error(&cmd_get), Some(Value::Error(
+            "ERR Can't execute 'get': only (P|S)SUBSCRIBE / (P|S)UNSUBSCRIBE / PING / QUIT / RESET are allowed in this context".to_string()
+        )));
+
+        let cmd_ping = vec![Value::BulkString(Bytes::from("ping"))];
+        assert_eq!(subscribed_context_error(&cmd_ping), None);
+    }
+
+    #[tokio::test]
+    async fn test_quit() {
+        let db = new_db();
+        let cmd = vec![Value::BulkString(Bytes::from("QUIT"))];
+        let resp = handle_command(&db, &mut ConnectionState::new(), &cmd).await;
+        assert_eq!(resp, Some(CommandResult::Close(Some(Value::SimpleString("OK".to_string())))));
+    }
+
+    #[test]
+    fn test_command_table_metadata() {
+        for (name, spec) in COMMAND_TABLE.iter() {
+            assert_eq!(*name, spec.name);
+            assert_eq!(spec.name, spec.name.to_uppercase(), "{} must be registered uppercase", name);
+            assert!(spec.arity != 0, "{} has no arity", name);
+            assert!(!(spec.is_write() && spec.flags.contains(&"readonly")), "{} is both write and readonly", name);
+            if spec.first_key > 0 {
+                // Fixed key positions have to fit in the smallest valid invocation
+                assert!(spec.first_key < spec.arity.abs(), "{} first key beyond arity", name);
+                assert!(spec.step > 0, "{} has keys but no step", name);
+                if spec.arity > 0 && spec.last_key > 0 {
+                    assert!(spec.last_key < spec.arity, "{} last key beyond arity", name);
+                }
+            } else {
+                assert_eq!((spec.first_key, spec.last_key, spec.step), (0, 0, 0), "{} key spec", name);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_arity_is_enforced_for_every_command() {
+        let db = new_db();
+        for spec in COMMAND_TABLE.values() {
+            let min_argc = spec.arity.unsigned_abs() as usize;
+            let too_short = min_argc - 1;
+            if too_short >= 1 {
+                let mut cmd = vec![Value::BulkString(Bytes::from(spec.name))];
+                cmd.resize(too_short, Value::BulkString(Bytes::from("x")));
+                let resp = handle_command(&db, &mut ConnectionState::new(), &cmd).await;
+                assert_eq!(resp, Some(CommandResult::Value(wrong_arity(&spec.name.to_lowercase()))), "{} accepted too few arguments", spec.name);
+            }
+            if spec.arity > 0 {
+                let mut cmd = vec![Value::BulkString(Bytes::from(spec.name))];
+                cmd.resize(min_argc + 1, Value::BulkString(Bytes::from("x")));
+                let resp = handle_command(&db, &mut ConnectionState::new(), &cmd).await;
+                assert_eq!(resp, Some(CommandResult::Value(wrong_arity(&spec.name.to_lowercase()))), "{} accepted too many arguments", spec.name);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_short_get_and_set() {
+        let db = new_db();
+        let cmd = vec![Value::BulkString(Bytes::from("GET"))];
+        let resp = handle_command(&db, &mut ConnectionState::new(), &cmd).await;
+        assert_eq!(resp, Some(CommandResult::Value(Value::Error(
+            "ERR wrong number of arguments for 'get' command".to_string()
+        ))));
+
+        let cmd = vec![
+            Value::BulkString(Bytes::from("set")),
+            Value::BulkString(Bytes::from("key")),
+        ];
+        let resp = handle_command(&db, &mut ConnectionState::new(), &cmd).await;
+        assert_eq!(resp, Some(CommandResult::Value(Value::Error(
+            "ERR wrong number of arguments for 'set' command".to_string()
+        ))));
+    }
+
+    #[tokio::test]
+    async fn test_mixed_case_command_names() {
+        let db = new_db();
+        for name in ["PING", "ping", "Ping", "pInG"] {
+            let cmd = vec![Value::BulkString(Bytes::from(name))];
+            let resp = handle_command(&db, &mut ConnectionState::new(), &cmd).await;
+            assert_eq!(resp, Some(CommandResult::Value(Value::SimpleString("PONG".to_string()))));
+        }
+    }
+
+    #[test]
+    fn test_lookup_command_bytes_hot_path() {
+        // Runs the lookup the way the dispatcher does on every command
+        for _ in 0..100_000 {
+            assert_eq!(lookup_command_bytes(b"get").map(|spec| spec.name), Some("GET"));
+            assert_eq!(lookup_command_bytes(b"SeT").map(|spec| spec.name), Some("SET"));
+        }
+        assert!(lookup_command_bytes(b"nosuchcommand").is_none());
+        assert!(lookup_command_bytes(&[b'a'; MAX_COMMAND_NAME + 1]).is_none());
+        assert!(lookup_command_bytes(&[0xff, 0xfe]).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_unknown_command_error() {
+        let db = new_db();
+        let cmd = vec![
+            Value::BulkString(Bytes::from("FOO")),
+            Value::BulkString(Bytes::from("bar")),
+        ];
+        let resp = handle_command(&db, &mut ConnectionState::new(), &cmd).await;
+        assert_eq!(resp, Some(CommandResult::Value(Value::Error(
+            "ERR unknown command 'FOO', with args beginning with: 'bar' ".to_string()
+        ))));
+
+        let cmd = vec![Value::BulkString(Bytes::from("nope"))];
+        let resp = handle_command(&db, &mut ConnectionState::new(), &cmd).await;
+        assert_eq!(resp, Some(CommandResult::Value(Value::Error(
+            "ERR unknown command 'nope', with args beginning with: ".to_string()
+        ))));
+    }
+
+    #[tokio::test]
+    async fn test_empty_command_gets_no_reply() {
+        let db = new_db();
+        assert_eq!(handle_command(&db, &mut ConnectionState::new(), &[]).await, None);
+    }
+
+    #[test]
+    fn test_is_int_encodable() {
+        let cases: &[(&str, Option<i64>)] = &[
+            ("0", Some(0)),
+            ("1", Some(1)),
+            ("-1", Some(-1)),
+            ("1234567890", Some(1234567890)),
+            ("9223372036854775807", Some(i64::MAX)),
+            ("-9223372036854775808", Some(i64::MIN)),
+            ("9223372036854775808", None),
+            ("-9223372036854775809", None),
+            ("00", None),
+            ("01", None),
+            ("-0", None),
+            ("-01", None),
+            ("+1", None),
+            (" 1", None),
+            ("1 ", None),
+            ("", None),
+            ("-", None),
+            ("1a", None),
+            ("1.0", None),
+        ];
+        for (input, expected) in cases {
+            assert_eq!(is_int_encodable(input.as_bytes()), *expected, "{:?}", input);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_numeric_argument_errors() {
+        let db = new_db();
+        let cmd = vec![
+            Value::BulkString(Bytes::from("LMPOP")),
+            Value::BulkString(Bytes::from("two")),
+            Value::BulkString(Bytes::from("a")),
+            Value::BulkString(Bytes::from("b")),
+            Value::BulkString(Bytes::from("LEFT")),
+        ];
+        let resp = handle_command(&db, &mut ConnectionState::new(), &cmd).await;
+        assert_eq!(resp, Some(CommandResult::Value(not_integer())));
+
+        // Huge numkeys must not be used to index past the arguments
+        let cmd = vec![
+            Value::BulkString(Bytes::from("LMPOP")),
+            Value::BulkString(Bytes::from("9223372036854775807")),
+            Value::BulkString(Bytes::from("a")),
+            Value::BulkString(Bytes::from("LEFT")),
+        ];
+        let resp = handle_command(&db, &mut ConnectionState::new(), &cmd).await;
+        assert_eq!(resp, Some(CommandResult::Value(syntax_error())));
+
+        let cmd = vec![
+            Value::BulkString(Bytes::from("LMPOP")),
+            Value::BulkString(Bytes::from("1")),
+            Value::BulkString(Bytes::from("a")),
+            Value::BulkString(Bytes::from("LEFT")),
+            Value::BulkString(Bytes::from("COUNT")),
+            Value::BulkString(Bytes::from("-3")),
+        ];
+        let resp = handle_command(&db, &mut ConnectionState::new(), &cmd).await;
+        assert_eq!(resp, Some(CommandResult::Value(Value::Error("ERR value is out of range, must be positive".to_string()))));
+
+        let cmd = vec![
+            Value::BulkString(Bytes::from("SCAN")),
+            Value::BulkString(Bytes::from("0")),
+            Value::BulkString(Bytes::from("COUNT")),
+            Value::BulkString(Bytes::from("99999999999999999999")),
+        ];
+        let resp = handle_command(&db, &mut ConnectionState::new(), &cmd).await;
+        assert_eq!(resp, Some(CommandResult::Value(not_integer())));
+    }
+
+    #[tokio::test]
+    async fn test_info_replication() {
+        let db = new_db();
+        let cmd = vec![
+            Value::BulkString(Bytes::from("INFO")),
+            Value::BulkString(Bytes::from("replication")),
+        ];
+        let info = match handle_command(&db, &mut ConnectionState::new(), &cmd).await {
+            Some(CommandResult::Value(Value::BulkString(bs))) => String::from_utf8(bs.to_vec()).unwrap(),
+            other => panic!("Expected bulk string, got {:?}", other),
+        };
+        assert!(info.starts_with("# Replication\r\n"));
+        assert!(info.contains("role:master\r\n"));
+        assert!(info.contains("connected_slaves:0\r\n"));
+        assert!(info.contains("master_failover_state:no-failover\r\n"));
+        let replid = info.lines()
+            .find_map(|line| line.strip_prefix("master_replid:"))
+            .expect("master_replid missing");
+        assert_eq!(replid.len(), 40);
+        assert!(replid.chars().all(|c| c.is_ascii_hexdigit()));
+    }
+
+    #[tokio::test]
+    async fn test_info_persistence() {
+        let db = new_db();
+        let cmd = vec![
+            Value::BulkString(Bytes::from("INFO")),
+            Value::BulkString(Bytes::from("persistence")),
+        ];
+        let info = match handle_command(&db, &mut ConnectionState::new(), &cmd).await {
+            Some(CommandResult::Value(Value::BulkString(bs))) => String::from_utf8(bs.to_vec()).unwrap(),
+            other => panic!("Expected bulk string, got {:?}", other),
+        };
+        assert!(info.starts_with("# Persistence\r\n"));
+        assert!(info.contains("loading:0\r\n"));
+        assert!(info.contains("rdb_bgsave_in_progress:0\r\n"));
+        assert!(info.contains("aof_enabled:0\r\n"));
+        let last_save: u64 = info.lines()
+            .find_map(|line| line.strip_prefix("rdb_last_save_time:"))
+            .expect("rdb_last_save_time missing")
+            .parse()
+            .unwrap();
+        assert!(crate::db::unix_time_secs() - last_save < 5);
+    }
+
+    #[tokio::test]
+    async fn test_info_run_id_is_stable() {
+        let db = new_db();
+        let cmd = vec![Value::BulkString(Bytes::from("INFO"))];
+        let mut run_ids = Vec::new();
+        for _ in 0..2 {
+            let info = match handle_command(&db, &mut ConnectionState::new(), &cmd).await {
+                Some(CommandResult::Value(Value::BulkString(bs))) => String::from_utf8(bs.to_vec()).unwrap(),
+                other => panic!("Expected bulk string, got {:?}", other),
+            };
+            let run_id = info.lines()
+                .find_map(|line| line.strip_prefix("run_id:"))
+                .expect("run_id missing")
+                .to_string();
+            assert_eq!(run_id.len(), 40);
+            assert!(run_id.chars().all(|c| c.is_ascii_hexdigit()));
+            run_ids.push(run_id);
+        }
+        assert_eq!(run_ids[0], run_ids[1]);
+    }
+
+    #[tokio::test]
+    async fn test_lcs() {
+        let db = new_db();
+        for (key, value) in [("key1", "ohmytext"), ("key2", "mynewtext")] {
+            let cmd = vec![
+                Value::BulkString(Bytes::from("SET")),
+                Value::BulkString(Bytes::from(key)),
+                Value::BulkString(Bytes::from(value)),
+            ];
+            handle_command(&db, &mut ConnectionState::new(), &cmd).await;
+        }
+
+        let lcs = |opts: &[&'static str]| {
+            let mut cmd = vec![
+                Value::BulkString(Bytes::from("LCS")),
+                Value::BulkString(Bytes::from("key1")),
+                Value::BulkString(Bytes::from("key2")),
+            ];
+            cmd.extend(opts.iter().map(|opt| Value::BulkString(Bytes::from(*opt))));
+            cmd
+        };
+        let span = |start: i64, end: i64| Value::Array(vec![Value::Integer(start), Value::Integer(end)]);
+
+        let resp = handle_command(&db, &mut ConnectionState::new(), &lcs(&[])).await;
+        assert_eq!(resp, Some(CommandResult::Value(Value::BulkString(Bytes::from("mytext")))));
+
+        let resp = handle_command(&db, &mut ConnectionState::new(), &lcs(&["LEN"])).await;
+        assert_eq!(resp, Some(CommandResult::Value(Value::Integer(6))));
+
+        let resp = handle_command(&db, &mut ConnectionState::new(), &lcs(&["IDX"])).await;
+        assert_eq!(resp, Some(CommandResult::Value(Value::Array(vec![
+            Value::BulkString(Bytes::from("matches")),
+            Value::Array(vec![
+                Value::Array(vec![span(4, 7), span(5, 8)]),
+                Value::Array(vec![span(2, 3), span(0, 1)]),
+            ]),
+            Value::BulkString(Bytes::from("len")),
+            Value::Integer(6),
+        ]))));
+
+        let resp = handle_command(&db, &mut ConnectionState::new(), &lcs(&["IDX", "MINMATCHLEN", "4", "WITHMATCHLEN"])).await;
+        assert_eq!(resp, Some(CommandResult::Value(Value::Array(vec![
+            Value::BulkString(Bytes::from("matches")),
+            Value::Array(vec![Value::Array(vec![span(4, 7), span(5, 8), Value::Integer(4)])]),
+            Value::BulkString(Bytes::from("len")),
+            Value::Integer(6),
+        ]))));
+
+        let resp = handle_command(&db, &mut ConnectionState::new(), &lcs(&["LEN", "IDX"])).await;
+        assert!(matches!(resp, Some(CommandResult::Value(Value::Error(_)))));
+
+        // A missing key is an empty string
+        let cmd = vec![
+            Value::BulkString(Bytes::from("LCS")),
+            Value::BulkString(Bytes::from("key1")),
+            Value::BulkString(Bytes::from("missing")),
+            Value::BulkString(Bytes::from("LEN")),
+        ];
+        let resp = handle_command(&db, &mut ConnectionState::new(), &cmd).await;
+        assert_eq!(resp, Some(CommandResult::Value(Value::Integer(0))));
+    }
+
+    #[tokio::test]
+    async fn test_lcs_table_too_large() {
+        let db = new_db();
+        // 10000 x 10000 u32 cells is ~400MB, over the cap
+        let long = Bytes::from(vec![b'a'; 10_000]);
+        insert(&db, "key1", DbValue::new_string(long.clone())).await;
+        insert(&db, "key2", DbValue::new_string(long)).await;
+        let cmd = vec![
+            Value::BulkString(Bytes::from("LCS")),
+            Value::BulkString(Bytes::from("key1")),
+            Value::BulkString(Bytes::from("key2")),
+        ];
+        let resp = handle_command(&db, &mut ConnectionState::new(), &cmd).await;
+        assert_eq!(resp, Some(CommandResult::Value(Value::Error("ERR String too long for LCS".to_string()))));
+    }
+
+    #[tokio::test]
+    async fn test_resp3_numeric_arguments() {
+        let db = new_db();
+        let cmd = vec![
+            Value::BulkString(Bytes::from("SET")),
+            Value::BulkString(Bytes::from("key")),
+            Value::Double(2.5),
+            Value::BulkString(Bytes::from("EX")),
+            Value::BigNumber("100".to_string()),
+        ];
+        let resp = handle_command(&db, &mut ConnectionState::new(), &cmd).await;
+        assert_eq!(resp, Some(CommandResult::Value(Value::SimpleString("OK".to_string()))));
+
+        let cmd = vec![Value::BulkString(Bytes::from("GET")), Value::BulkString(Bytes::from("key"))];
+        let resp = handle_command(&db, &mut ConnectionState::new(), &cmd).await;
+        assert_eq!(resp, Some(CommandResult::Value(Value::BulkString(Bytes::from("2.5")))));
+    }
+
+    #[tokio::test]
+    async fn test_expire_and_ttl() {
+        let clock = Arc::new(MockClock::new());
+        let db = new_db_with_clock(clock.clone());
+        let cmd_ttl = vec![
+            Value::BulkString(Bytes::from("TTL")),
+            Value::BulkString(Bytes::from("key")),
+        ];
+        let cmd_expire = |secs: &'static str| vec![
+            Value::BulkString(Bytes::from("EXPIRE")),
+            Value::BulkString(Bytes::from("key")),
+            Value::BulkString(Bytes::from(secs)),
+        ];
+
+        // Missing key
+        let resp = handle_command(&db, &mut ConnectionState::new(), &cmd_expire("10")).await;
+        assert_eq!(resp, Some(CommandResult::Value(Value::Integer(0))));
+        let resp = handle_command(&db, &mut ConnectionState::new(), &cmd_ttl).await;
+        assert_eq!(resp, Some(CommandResult::Value(Value::Integer(-2))));
+
+        let cmd_set = vec![
+            Value::BulkString(Bytes::from("SET")),
+            Value::BulkString(Bytes::from("key")),
+            Value::BulkString(Bytes::from("value")),
+        ];
+        handle_command(&db, &mut ConnectionState::new(), &cmd_set).await;
+        let resp = handle_command(&db, &mut ConnectionState::new(), &cmd_ttl).await;
+        assert_eq!(resp, Some(CommandResult::Value(Value::Integer(-1))));
+
+        let resp = handle_command(&db, &mut ConnectionState::new(), &cmd_expire("10")).await;
+        assert_eq!(resp, Some(CommandResult::Value(Value::Integer(1))));
+        let resp = handle_command(&db, &mut ConnectionState::new(), &cmd_ttl).await;
+        assert_eq!(resp, Some(CommandResult::Value(Value::Integer(10))));
+
+        // Partial seconds round down
+        clock.advance(Duration::from_millis(1500));
+        let resp = handle_command(&db, &mut ConnectionState::new(), &cmd_ttl).await;
+        assert_eq!(resp, Some(CommandResult::Value(Value::Integer(8))));
+
+        clock.advance(Duration::from_secs(9));
+        let resp = handle_command(&db, &mut ConnectionState::new(), &cmd_ttl).await;
+        assert_eq!(resp, Some(CommandResult::Value(Value::Integer(-2))));
+    }
+
+    #[tokio::test]
+    async fn test_persist() {
+        let clock = Arc::new(MockClock::new());
+        let db = new_db_with_clock(clock.clone());
+        let cmd_persist = vec![
+            Value::BulkString(Bytes::from("PERSIST")),
+            Value::BulkString(Bytes::from("key")),
+        ];
+        let resp = handle_command(&db, &mut ConnectionState::new(), &cmd_persist).await;
+        assert_eq!(resp, Some(CommandResult::Value(Value::Integer(0))));
+
+        let cmd_set = vec![
+            Value::BulkString(Bytes::from("SET")),
+            Value::BulkString(Bytes::from("key")),
+            Value::BulkString(Bytes::from("value")),
+            Value::BulkString(Bytes::from("EX")),
+            Value::BulkString(Bytes::from("10")),
+        ];
+        handle_command(&db, &mut ConnectionState::new(), &cmd_set).await;
+        let resp = handle_command(&db, &mut ConnectionState::new(), &cmd_persist).await;
+        assert_eq!(resp, Some(CommandResult::Value(Value::Integer(1))));
+        // Nothing left to clear
+        let resp = handle_command(&db, &mut ConnectionState::new(), &cmd_persist).await;
+        assert_eq!(resp, Some(CommandResult::Value(Value::Integer(0))));
+
+        clock.advance(Duration::from_secs(20));
+        let cmd_get = vec![Value::BulkString(Bytes::from("GET")), Value::BulkString(Bytes::from("key"))];
+        let resp = handle_command(&db, &mut ConnectionState::new(), &cmd_get).await;
+        assert_eq!(resp, Some(CommandResult::Value(Value::BulkString(Bytes::from("value")))));
+
+        // An expired key counts as missing and is removed
+        handle_command(&db, &mut ConnectionState::new(), &cmd_set).await;
+        clock.advance(Duration::from_secs(20));
+        let resp = handle_command(&db, &mut ConnectionState::new(), &cmd_persist).await;
+        assert_eq!(resp, Some(CommandResult::Value(Value::Integer(0))));
+        assert!(!db.shard("key").read().await.contains_key("key"));
+    }
+
+    #[tokio::test]
+    async fn test_expire_non_positive_deletes() {
+        let db = new_db();
+        let cmd_exists = vec![
+            Value::BulkString(Bytes::from("EXISTS")),
+            Value::BulkString(Bytes::from("key")),
+        ];
+        for secs in ["0", "-5"] {
+            let cmd_set = vec![
+                Value::BulkString(Bytes::from("SET")),
+                Value::BulkString(Bytes::from("key")),
+                Value::BulkString(Bytes::from("value")),
+            ];
+            handle_command(&db, &mut ConnectionState::new(), &cmd_set).await;
+            let cmd_expire = vec![
+                Value::BulkString(Bytes::from("EXPIRE")),
+                Value::BulkString(Bytes::from("key")),
+                Value::BulkString(Bytes::from(secs)),
+            ];
+            let resp = handle_command(&db, &mut ConnectionState::new(), &cmd_expire).await;
+            assert_eq!(resp, Some(CommandResult::Value(Value::Integer(1))));
+            let resp = handle_command(&db, &mut ConnectionState::new(), &cmd_exists).await;
+            assert_eq!(resp, Some(CommandResult::Value(Value::Integer(0))));
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_sets_on_other_shards_dont_wait_for_a_held_shard() {
+        let db = new_db();
+        let set = |key: String| {
+            let db = db.clone();
+            async move {
+                let cmd = vec![
+                    Value::BulkString(Bytes::from("SET")),
+                    Value::BulkString(Bytes::from(key)),
+                    Value::BulkString(Bytes::from("v")),
+                ];
+                handle_command(&db, &mut ConnectionState::new(), &cmd).await
+            }
+        };
+        let held = db.shard("held").write().await;
+
+        let keys: Vec<String> = (0..64)
+            .map(|i| format!("key{}", i))
+            .filter(|key| !std::ptr::eq(db.shard(key), db.shard("held")))
+            .collect();
+        let sets: Vec<_> = keys.iter().cloned().map(|key| tokio::spawn(set(key))).collect();
+        for handle in sets {
+            let resp = tokio::time::timeout(Duration::from_secs(1), handle).await
+                .expect("SET on an unlocked shard blocked").unwrap();
+            assert_eq!(resp, Some(CommandResult::Value(Value::SimpleString("OK".to_string()))));
+        }
+
+        // A SET on the held shard waits until the lock is released
+        let blocked = tokio::spawn(set("held".to_string()));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!blocked.is_finished());
+        drop(held);
+        assert!(blocked.await.unwrap().is_some());
+        let cmd_dbsize = vec![Value::BulkString(Bytes::from("DBSIZE"))];
+        let resp = handle_command(&db, &mut ConnectionState::new(), &cmd_dbsize).await;
+        assert_eq!(resp, Some(CommandResult::Value(Value::Integer(keys.len() as i64 + 1))));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_mget_never_sees_half_an_mset() {
+        let db = new_db();
+        // One key per shard, so every MSET and MGET spans them all
+        let mut keys: Vec<String> = Vec::new();
+        for key in (0..).map(|i| format!("key{}", i)) {
+            if !keys.iter().any(|k| std::ptr::eq(db.shard(k), db.shard(&key))) {
+                keys.push(key);
+            }
+            if keys.len() == crate::db::SHARD_COUNT {
+                break;
+            }
+        }
+        let mset = |value: &str| {
+            let mut cmd = vec![Value::BulkString(Bytes::from("MSET"))];
+            for key in &keys {
+                cmd.push(Value::BulkString(Bytes::from(key.clone())));
+                cmd.push(Value::BulkString(Bytes::from(value.to_string())));
+            }
+            cmd
+        };
+        let mut cmd_mget = vec![Value::BulkString(Bytes::from("MGET"))];
+        cmd_mget.extend(keys.iter().map(|key| Value::BulkString(Bytes::from(key.clone()))));
+        handle_command(&db, &mut ConnectionState::new(), &mset("old")).await;
+
+        let writers: Vec<_> = ["old", "new"]
+            .into_iter()
+            .map(|value| {
+                let (db, cmd) = (db.clone(), mset(value));
+                tokio::spawn(async move {
+                    for _ in 0..200 {
+                        handle_command(&db, &mut ConnectionState::new(), &cmd).await;
+                    }
+                })
+            })
+            .collect();
+        let readers: Vec<_> = (0..2)
+            .map(|_| {
+                let (db, cmd) = (db.clone(), cmd_mget.clone());
+                tokio::spawn(async move {
+                    for _ in 0..200 {
+                        let Some(CommandResult::Value(Value::Array(values))) =
+                            handle_command(&db, &mut ConnectionState::new(), &cmd).await
+                        else {
+                            panic!("expected an array");
+                        };
+                        assert!(values.iter().all(|v| *v == values[0]), "torn MGET: {:?}", values);
+                    }
+                })
+            })
+            .collect();
+        for handle in writers.into_iter().chain(readers) {
+            handle.await.unwrap();
+        }
+    }
 }
\ No newline at end of file
@@ -0,0 +1,362 @@
+use std::time::Duration;
+use thiserror::Error;
+
+#[derive(Error, Debug, PartialEq)]
+pub enum ConfigError {
+    #[error("Unknown option '{0}'")]
+    UnknownOption(String),
+    #[error("Missing value for '{0}'")]
+    MissingValue(String),
+    #[error("Invalid value '{1}' for '{0}'")]
+    InvalidValue(String, String),
+}
+
+/// `client-output-buffer-limit` for one client class. A client is disconnected once
+/// its queued output goes over `hard` bytes, or stays over `soft` bytes for
+/// `soft_duration`. A limit of 0 is no limit.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct OutputBufferLimit {
+    pub hard: u64,
+    pub soft: u64,
+    pub soft_duration: Duration,
+}
+
+/// Server settings, named after their redis.conf counterparts
+#[derive(Debug, Clone, PartialEq)]
+pub struct ServerConfig {
+    /// Interfaces to listen on: IP addresses or hostnames, each optionally with its
+    /// own `:port` (IPv6 addresses in brackets then)
+    pub bind: Vec<String>,
+    /// Port for `bind` entries that don't name one
+    pub port: u16,
+    pub tcp_backlog: u32,
+    /// Memory limit in bytes, 0 means no limit
+    pub maxmemory: u64,
+    /// Accept a bare `\n` as the end of an inline command
+    pub lenient_newlines: bool,
+    /// How long a partially received command may sit before the connection is
+    /// closed (`proto-read-timeout`, in milliseconds, 0 disables)
+    pub proto_read_timeout: Duration,
+    /// Background task frequency, in ticks per second (1 to 500)
+    pub hz: u32,
+    /// Keys the active-expiration task samples per tick (`active-expire-samples`)
+    pub active_expire_samples: usize,
+    /// SET on a key holding another type replies WRONGTYPE instead of overwriting it
+    pub set_strict_type: bool,
+    /// Output buffer limit for connections in subscribed state
+    pub pubsub_output_limit: OutputBufferLimit,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            bind: vec!["127.0.0.1".to_string()],
+            port: 6379,
+            tcp_backlog: 511,
+            maxmemory: 0,
+            lenient_newlines: false,
+            proto_read_timeout: Duration::from_secs(30),
+            hz: 10,
+            active_expire_samples: 20,
+            set_strict_type: false,
+            pubsub_output_limit: OutputBufferLimit {
+                hard: 32 * 1024 * 1024,
+                soft: 8 * 1024 * 1024,
+                soft_duration: Duration::from_secs(60),
+            },
+        }
+    }
+}
+
+impl ServerConfig {
+    /// Parses redis-server style `--name value` command line arguments, plus an
+    /// optional positional `host:port`
+    pub fn from_args<I: IntoIterator<Item = String>>(args: I) -> Result<Self, ConfigError> {
+        let mut config = Self::default();
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let name = match arg.strip_prefix("--") {
+                Some(name) => name.to_string(),
+                None => {
+                    let Some((host, Some(port))) = split_host_port(&arg) else {
+                        return Err(ConfigError::UnknownOption(arg));
+                    };
+                    config.bind = vec![host.to_string()];
+                    config.port = port;
+                    continue;
+                }
+            };
+            let value = args.next().ok_or_else(|| ConfigError::MissingValue(name.clone()))?;
+            config.set(&name, &value)?;
+        }
+        Ok(config)
+    }
+
+    pub fn set(&mut self, name: &str, value: &str) -> Result<(), ConfigError> {
+        match name.to_lowercase().as_str() {
+            // A comma or space separated list; `host` is kept as an alias
+            "bind" | "host" => {
+                let entries: Vec<String> = value
+                    .split(|c: char| c == ',' || c.is_whitespace())
+                    .filter(|entry| !entry.is_empty())
+                    .map(str::to_string)
+                    .collect();
+                if entries.is_empty() || entries.iter().any(|entry| split_host_port(entry).is_none()) {
+                    return Err(ConfigError::InvalidValue(name.to_string(), value.to_string()));
+                }
+                self.bind = entries;
+            }
+            "port" => self.port = parse_number(name, value)?,
+            "tcp-backlog" => self.tcp_backlog = parse_number(name, value)?,
+            "maxmemory" => {
+                self.maxmemory = parse_memory_size(value)
+                    .ok_or_else(|| ConfigError::InvalidValue(name.to_string(), value.to_string()))?
+            }
+            "lenient-newlines" => self.lenient_newlines = parse_bool(name, value)?,
+            "proto-read-timeout" => self.proto_read_timeout = Duration::from_millis(parse_number(name, value)?),
+            "client-output-buffer-limit" => self.pubsub_output_limit = parse_output_buffer_limit(name, value)?,
+            "set-strict-type" => self.set_strict_type = parse_bool(name, value)?,
+            "hz" => match parse_number(name, value)? {
+                hz @ 1..=500 => self.hz = hz,
+                _ => return Err(ConfigError::InvalidValue(name.to_string(), value.to_string())),
+            },
+            "active-expire-samples" => match parse_number(name, value)? {
+                0 => return Err(ConfigError::InvalidValue(name.to_string(), value.to_string())),
+                samples => self.active_expire_samples = samples,
+            },
+            _ => return Err(ConfigError::UnknownOption(name.to_string())),
+        }
+        Ok(())
+    }
+
+    /// `host:port` for every `bind` entry, ready to resolve, with IPv6 addresses in brackets
+    pub fn bind_addrs(&self) -> Vec<String> {
+        self.bind
+            .iter()
+            .filter_map(|entry| split_host_port(entry))
+            .map(|(host, port)| {
+                let port = port.unwrap_or(self.port);
+                if host.contains(':') { format!("[{}]:{}", host, port) } else { format!("{}:{}", host, port) }
+            })
+            .collect()
+    }
+
+    /// Time between background ticks at the configured `hz`
+    pub fn tick_interval(&self) -> Duration {
+        Duration::from_secs(1) / self.hz
+    }
+}
+
+/// Splits `host`, `host:port`, a bare IPv6 address or `[ipv6]:port` into the host
+/// and the port if there is one
+fn split_host_port(entry: &str) -> Option<(&str, Option<u16>)> {
+    let (host, port) = match entry.strip_prefix('[') {
+        Some(rest) => {
+            let (host, rest) = rest.split_once(']')?;
+            match rest {
+                "" => (host, None),
+                _ => (host, Some(rest.strip_prefix(':')?)),
+            }
+        }
+        // More than one colon is an IPv6 address without a port
+        None => match entry.split_once(':') {
+            Some((host, port)) if !port.contains(':') => (host, Some(port)),
+            _ => (entry, None),
+        },
+    };
+    if host.is_empty() {
+        return None;
+    }
+    match port {
+        Some(port) => Some((host, Some(port.parse().ok()?))),
+        None => Some((host, None)),
+    }
+}
+
+fn parse_number<T: std::str::FromStr>(name: &str, value: &str) -> Result<T, ConfigError> {
+    value.parse().map_err(|_| ConfigError::InvalidValue(name.to_string(), value.to_string()))
+}
+
+fn parse_bool(name: &str, value: &str) -> Result<bool, ConfigError> {
+    match value.to_lowercase().as_str() {
+        "yes" => Ok(true),
+        "no" => Ok(false),
+        _ => Err(ConfigError::InvalidValue(name.to_string(), value.to_string())),
+    }
+}
+
+/// Parses `pubsub <hard> <soft> <soft seconds>`, the only client class there is here
+fn parse_output_buffer_limit(name: &str, value: &str) -> Result<OutputBufferLimit, ConfigError> {
+    let invalid = || ConfigError::InvalidValue(name.to_string(), value.to_string());
+    match value.split_whitespace().collect::<Vec<_>>()[..] {
+        [class, hard, soft, secs] if class.eq_ignore_ascii_case("pubsub") => Ok(OutputBufferLimit {
+            hard: parse_memory_size(hard).ok_or_else(invalid)?,
+            soft: parse_memory_size(soft).ok_or_else(invalid)?,
+            soft_duration: Duration::from_secs(secs.parse().map_err(|_| invalid())?),
+        }),
+        _ => Err(invalid()),
+    }
+}
+
+/// Parses a redis.conf memory size like `512`, `100mb` or `1gb` into bytes.
+/// As in Redis, `k`/`m`/`g` are powers of 1000 and `kb`/`mb`/`gb` powers of 1024.
+pub fn parse_memory_size(value: &str) -> Option<u64> {
+    let value = value.to_ascii_lowercase();
+    let split = value.find(|c: char| !c.is_ascii_digit()).unwrap_or(value.len());
+    let (digits, unit) = value.split_at(split);
+    let multiplier: u64 = match unit {
+        "" | "b" => 1,
+        "k" => 1000,
+        "kb" => 1024,
+        "m" => 1000 * 1000,
+        "mb" => 1024 * 1024,
+        "g" => 1000 * 1000 * 1000,
+        "gb" => 1024 * 1024 * 1024,
+        _ => return None,
+    };
+    digits.parse::<u64>().ok()?.checked_mul(multiplier)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(list: &[&str]) -> Vec<String> {
+        list.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn test_defaults() {
+        let config = ServerConfig::from_args(args(&[])).unwrap();
+        assert_eq!(config.bind_addrs(), vec!["127.0.0.1:6379"]);
+        assert_eq!(config.tcp_backlog, 511);
+        assert_eq!(config.maxmemory, 0);
+        assert!(!config.lenient_newlines);
+        assert_eq!(config.proto_read_timeout, Duration::from_secs(30));
+        assert_eq!(config.tick_interval(), Duration::from_millis(100));
+        assert_eq!(config.active_expire_samples, 20);
+        assert!(!config.set_strict_type);
+    }
+
+    #[test]
+    fn test_parse_tcp_backlog() {
+        let config = ServerConfig::from_args(args(&["--tcp-backlog", "1024"])).unwrap();
+        assert_eq!(config.tcp_backlog, 1024);
+    }
+
+    #[test]
+    fn test_parse_memory_size() {
+        assert_eq!(parse_memory_size("100mb"), Some(100 * 1024 * 1024));
+        assert_eq!(parse_memory_size("1gb"), Some(1024 * 1024 * 1024));
+        assert_eq!(parse_memory_size("1G"), Some(1_000_000_000));
+        assert_eq!(parse_memory_size("512"), Some(512));
+        assert_eq!(parse_memory_size("512kb"), Some(512 * 1024));
+        assert_eq!(parse_memory_size("10xb"), None);
+        assert_eq!(parse_memory_size("mb"), None);
+        assert_eq!(parse_memory_size("-5mb"), None);
+    }
+
+    #[test]
+    fn test_parse_maxmemory() {
+        let config = ServerConfig::from_args(args(&["--maxmemory", "100mb"])).unwrap();
+        assert_eq!(config.maxmemory, 100 * 1024 * 1024);
+    }
+
+    #[test]
+    fn test_parse_lenient_newlines() {
+        let config = ServerConfig::from_args(args(&["--lenient-newlines", "yes"])).unwrap();
+        assert!(config.lenient_newlines);
+        assert!(ServerConfig::from_args(args(&["--lenient-newlines", "maybe"])).is_err());
+    }
+
+    #[test]
+    fn test_parse_proto_read_timeout() {
+        let config = ServerConfig::from_args(args(&["--proto-read-timeout", "250"])).unwrap();
+        assert_eq!(config.proto_read_timeout, Duration::from_millis(250));
+    }
+
+    #[test]
+    fn test_parse_active_expiration() {
+        let config = ServerConfig::from_args(args(&["--hz", "50", "--active-expire-samples", "100"])).unwrap();
+        assert_eq!(config.hz, 50);
+        assert_eq!(config.tick_interval(), Duration::from_millis(20));
+        assert_eq!(config.active_expire_samples, 100);
+
+        for (name, value) in [("--hz", "0"), ("--hz", "501"), ("--active-expire-samples", "0")] {
+            assert_eq!(
+                ServerConfig::from_args(args(&[name, value])),
+                Err(ConfigError::InvalidValue(name[2..].to_string(), value.to_string()))
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_set_strict_type() {
+        let config = ServerConfig::from_args(args(&["--set-strict-type", "yes"])).unwrap();
+        assert!(config.set_strict_type);
+    }
+
+    #[test]
+    fn test_parse_client_output_buffer_limit() {
+        let config = ServerConfig::from_args(args(&["--client-output-buffer-limit", "pubsub 1mb 256kb 10"])).unwrap();
+        assert_eq!(config.pubsub_output_limit, OutputBufferLimit {
+            hard: 1024 * 1024,
+            soft: 256 * 1024,
+            soft_duration: Duration::from_secs(10),
+        });
+
+        for value in ["normal 0 0 0", "pubsub 1mb 256kb", "pubsub 1mb 256kb ten"] {
+            assert_eq!(
+                ServerConfig::from_args(args(&["--client-output-buffer-limit", value])),
+                Err(ConfigError::InvalidValue("client-output-buffer-limit".to_string(), value.to_string()))
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert_eq!(
+            ServerConfig::from_args(args(&["--tcp-backlog", "lots"])),
+            Err(ConfigError::InvalidValue("tcp-backlog".to_string(), "lots".to_string()))
+        );
+        assert_eq!(
+            ServerConfig::from_args(args(&["--tcp-backlog"])),
+            Err(ConfigError::MissingValue("tcp-backlog".to_string()))
+        );
+        assert_eq!(
+            ServerConfig::from_args(args(&["--bogus", "1"])),
+            Err(ConfigError::UnknownOption("bogus".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_parse_host_and_port() {
+        let config = ServerConfig::from_args(args(&["--host", "0.0.0.0", "--port", "7000"])).unwrap();
+        assert_eq!(config.bind_addrs(), vec!["0.0.0.0:7000"]);
+
+        let config = ServerConfig::from_args(args(&["localhost:6380", "--hz", "20"])).unwrap();
+        assert_eq!((config.bind, config.port), (vec!["localhost".to_string()], 6380));
+
+        let config = ServerConfig::from_args(args(&["[::1]:6381"])).unwrap();
+        assert_eq!(config.bind_addrs(), vec!["[::1]:6381"]);
+
+        assert!(ServerConfig::from_args(args(&["--port", "65536"])).is_err());
+        assert!(ServerConfig::from_args(args(&["6379"])).is_err());
+    }
+
+    #[test]
+    fn test_parse_bind_list() {
+        let config = ServerConfig::from_args(args(&["--bind", "127.0.0.1, 0.0.0.0:6380,::1", "--port", "7000"])).unwrap();
+        assert_eq!(config.bind_addrs(), vec!["127.0.0.1:7000", "0.0.0.0:6380", "[::1]:7000"]);
+
+        let config = ServerConfig::from_args(args(&["--bind", "[::1]:6390 localhost"])).unwrap();
+        assert_eq!(config.bind_addrs(), vec!["[::1]:6390", "localhost:6379"]);
+
+        for bad in ["", ",", "127.0.0.1:port", "[::1", ":6379"] {
+            assert_eq!(
+                ServerConfig::from_args(args(&["--bind", bad])),
+                Err(ConfigError::InvalidValue("bind".to_string(), bad.to_string()))
+            );
+        }
+    }
+}
\ No newline at end of file
@@ -0,0 +1,68 @@
+use crate::resp::Value;
+use bytes::Bytes;
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+static NEXT_CLIENT_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Per-connection state that commands can read and change, handed to
+/// `handle_command` alongside the shared database
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConnectionState {
+    /// Unique for the lifetime of the process, as reported by CLIENT ID
+    pub id: u64,
+    /// RESP protocol version the client speaks, 2 until HELLO says otherwise
+    pub protocol: u8,
+    /// Index of the selected database
+    pub db: usize,
+    /// Set with CLIENT SETNAME
+    pub name: Option<Bytes>,
+    pub authenticated: bool,
+    /// Channels joined with SUBSCRIBE
+    pub channels: HashSet<Bytes>,
+    /// Channels joined with SSUBSCRIBE
+    pub shard_channels: HashSet<Bytes>,
+    /// Commands queued since MULTI, `None` outside a transaction. Nothing queues
+    /// commands yet; this is where MULTI/EXEC will keep them.
+    pub queued: Option<Vec<Vec<Value>>>,
+}
+
+impl ConnectionState {
+    pub fn new() -> Self {
+        Self::with_id(NEXT_CLIENT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+
+    fn with_id(id: u64) -> Self {
+        Self {
+            id,
+            protocol: 2,
+            db: 0,
+            name: None,
+            authenticated: true,
+            channels: HashSet::new(),
+            shard_channels: HashSet::new(),
+            queued: None,
+        }
+    }
+
+    /// How many channels of one kind the connection is subscribed to
+    pub fn subscription_count(&self, sharded: bool) -> i64 {
+        if sharded { self.shard_channels.len() as i64 } else { self.channels.len() as i64 }
+    }
+
+    pub fn is_subscribed(&self) -> bool {
+        !self.channels.is_empty() || !self.shard_channels.is_empty()
+    }
+
+    /// Back to how a new connection starts, keeping only the client id (RESET).
+    /// Subscriptions must already have been dropped from the database.
+    pub fn reset(&mut self) {
+        *self = Self::with_id(self.id);
+    }
+}
+
+impl Default for ConnectionState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
\ No newline at end of file
@@ -1,8 +1,11 @@
 use std::collections::HashMap;
-use std::sync::Arc;
-use std::time::{Duration, Instant};
-use tokio::sync::{RwLock, mpsc::Sender};
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::{RwLock, RwLockWriteGuard, Notify, mpsc::UnboundedSender};
 use bytes::Bytes;
+use crate::config::{OutputBufferLimit, ServerConfig};
 
 #[derive(Clone, Debug, PartialEq)]
 pub enum DataType {
@@ -16,18 +19,445 @@ pub struct DbValue {
     pub expiry: Option<Instant>,
 }
 
+/// Which commands a CLIENT PAUSE holds back
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PauseMode {
+    Write,
+    All,
+}
+
+/// Server-wide CLIENT PAUSE state; waiters are woken through `notify` on UNPAUSE
+pub struct ClientPause {
+    pub deadline: Option<Instant>,
+    pub mode: PauseMode,
+    pub notify: Arc<Notify>,
+}
+
+/// A published message on its way to a subscribed connection
+#[derive(Clone, Debug, PartialEq)]
+pub struct PubSubMessage {
+    pub sharded: bool,
+    pub channel: Bytes,
+    pub payload: Bytes,
+}
+
+impl PubSubMessage {
+    /// Roughly what the message costs in a subscriber's output buffer
+    pub fn size(&self) -> usize {
+        self.channel.len() + self.payload.len()
+    }
+}
+
+/// How much published data is queued for one subscribed connection but not yet
+/// written out. Shared by the publishers and the connection draining the queue.
+#[derive(Debug, Default)]
+pub struct OutputBuffer {
+    queued: AtomicUsize,
+    over_soft_since: Mutex<Option<Instant>>,
+    closed: Notify,
+}
+
+impl OutputBuffer {
+    /// Accounts for `bytes` about to be queued. If that breaks `limit` the message
+    /// must be dropped: returns false and tells the connection to close.
+    pub fn reserve(&self, bytes: usize, limit: &OutputBufferLimit, now: Instant) -> bool {
+        let queued = (self.queued.load(Ordering::Acquire) + bytes) as u64;
+        let over_hard = limit.hard > 0 && queued > limit.hard;
+        let over_soft = {
+            let mut since = self.over_soft_since.lock().unwrap();
+            if limit.soft > 0 && queued > limit.soft {
+                now.duration_since(*since.get_or_insert(now)) >= limit.soft_duration
+            } else {
+                *since = None;
+                false
+            }
+        };
+        if over_hard || over_soft {
+            self.closed.notify_one();
+            return false;
+        }
+        self.queued.fetch_add(bytes, Ordering::AcqRel);
+        true
+    }
+
+    /// Accounts for `bytes` taken off the queue
+    pub fn release(&self, bytes: usize) {
+        self.queued.fetch_sub(bytes, Ordering::AcqRel);
+    }
+
+    /// Resolves once a publisher has found the connection over its limit
+    pub async fn closed(&self) {
+        self.closed.notified().await
+    }
+}
+
+/// One connection's subscription to a channel
+#[derive(Clone, Debug)]
+pub struct Subscription {
+    pub tx: UnboundedSender<PubSubMessage>,
+    pub buffer: Arc<OutputBuffer>,
+}
+
+impl Subscription {
+    pub fn new(tx: UnboundedSender<PubSubMessage>) -> Self {
+        Self { tx, buffer: Arc::default() }
+    }
+}
+
+/// How many independently locked pieces the keyspace is split into
+pub const SHARD_COUNT: usize = 16;
+
+/// One piece of the keyspace. Every key lives in the shard `Database::shard` picks
+/// for it, so commands on keys in different shards don't wait on each other.
+pub struct Shard {
+    data: HashMap<String, DbValue>,
+    clock: Arc<dyn Clock>,
+    /// Keys removed because their TTL ran out, lazily or by active expiration
+    pub expired_keys: u64,
+    /// Exactly the keys in `data` that have an expiry, so active expiration can sample
+    /// them without walking the whole shard. `data` is private so every insert,
+    /// removal and expiry change goes through a method that keeps this in step.
+    volatile: Vec<String>,
+    /// Position of each key in `volatile`
+    volatile_index: HashMap<String, usize>,
+}
+
 pub struct Database {
-    pub data: HashMap<String, DbValue>,
-    pub channels: HashMap<String, Vec<Sender<Bytes>>>,
+    shards: Box<[RwLock<Shard>]>,
+    /// Shard the next active-expiration tick starts from
+    expire_cursor: AtomicUsize,
+    pub channels: RwLock<HashMap<Bytes, Vec<Subscription>>>,
+    /// Sharded (SSUBSCRIBE/SPUBLISH) channels, a namespace separate from `channels`
+    pub shard_channels: RwLock<HashMap<Bytes, Vec<Subscription>>>,
+    pub pause: RwLock<ClientPause>,
+    pub clock: Arc<dyn Clock>,
+    /// Run and replication IDs reported by INFO, fixed for the life of the process
+    pub run_id: String,
+    pub replid: String,
+    /// Unix time of the last save. Nothing is persisted, so this stays at startup, as in Redis.
+    pub last_save_time: u64,
+    /// `set-strict-type`: SET refuses to replace a non-string value
+    pub set_strict_type: bool,
+    pub pubsub_output_limit: OutputBufferLimit,
+}
+
+pub type Db = Arc<Database>;
+
+/// Write guards on the shards a multi-key command needs
+pub struct LockedShards<'a> {
+    db: &'a Database,
+    /// Sorted by shard index
+    guards: Vec<(usize, RwLockWriteGuard<'a, Shard>)>,
+}
+
+impl<'a> LockedShards<'a> {
+    /// The locked shard holding `key`. Panics if `key` wasn't one of the keys locked for.
+    pub fn get_mut(&mut self, key: &str) -> &mut Shard {
+        let index = self.db.shard_index(key);
+        let pos = self.guards.binary_search_by_key(&index, |(i, _)| *i)
+            .expect("key's shard was not locked");
+        &mut self.guards[pos].1
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Shard> {
+        self.guards.iter().map(|(_, guard)| &**guard)
+    }
+
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = &mut Shard> + use<'_, 'a> {
+        self.guards.iter_mut().map(|(_, guard)| &mut **guard)
+    }
+}
+
+/// Time source for expiry, swappable so tests can move time without sleeping
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// Clock that only moves when `advance` is called
+pub struct MockClock {
+    now: std::sync::Mutex<Instant>,
 }
 
-pub type Db = Arc<RwLock<Database>>;
+impl MockClock {
+    pub fn new() -> Self {
+        Self { now: std::sync::Mutex::new(Instant::now()) }
+    }
+
+    pub fn advance(&self, by: Duration) {
+        *self.now.lock().unwrap() += by;
+    }
+}
+
+impl Default for MockClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        *self.now.lock().unwrap()
+    }
+}
 
 pub fn new_db() -> Db {
-    Arc::new(RwLock::new(Database {
-        data: HashMap::new(),
-        channels: HashMap::new(),
-    }))
+    new_db_with(Arc::new(SystemClock), &ServerConfig::default())
+}
+
+pub fn new_db_with_clock(clock: Arc<dyn Clock>) -> Db {
+    new_db_with(clock, &ServerConfig::default())
+}
+
+pub fn new_db_with_config(config: &ServerConfig) -> Db {
+    new_db_with(Arc::new(SystemClock), config)
+}
+
+fn new_db_with(clock: Arc<dyn Clock>, config: &ServerConfig) -> Db {
+    let shards = (0..SHARD_COUNT)
+        .map(|_| RwLock::new(Shard {
+            data: HashMap::new(),
+            clock: clock.clone(),
+            expired_keys: 0,
+            volatile: Vec::new(),
+            volatile_index: HashMap::new(),
+        }))
+        .collect();
+    Arc::new(Database {
+        shards,
+        expire_cursor: AtomicUsize::new(0),
+        channels: RwLock::new(HashMap::new()),
+        shard_channels: RwLock::new(HashMap::new()),
+        pause: RwLock::new(ClientPause {
+            deadline: None,
+            mode: PauseMode::All,
+            notify: Arc::new(Notify::new()),
+        }),
+        clock,
+        run_id: random_hex_id(),
+        replid: random_hex_id(),
+        last_save_time: unix_time_secs(),
+        set_strict_type: config.set_strict_type,
+        pubsub_output_limit: config.pubsub_output_limit,
+    })
+}
+
+pub fn unix_time_secs() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
+
+pub fn unix_time_millis() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_millis() as u64)
+}
+
+/// 40 random hex characters, the format Redis uses for run and replication IDs
+pub fn random_hex_id() -> String {
+    use rand::Rng;
+    let mut rng = rand::thread_rng();
+    (0..40).map(|_| format!("{:x}", rng.gen_range(0..16u8))).collect()
+}
+
+impl Database {
+    pub fn now(&self) -> Instant {
+        self.clock.now()
+    }
+
+    fn shard_index(&self, key: &str) -> usize {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        (hasher.finish() % self.shards.len() as u64) as usize
+    }
+
+    /// The shard `key` lives in
+    pub fn shard(&self, key: &str) -> &RwLock<Shard> {
+        &self.shards[self.shard_index(key)]
+    }
+
+    pub fn shards(&self) -> &[RwLock<Shard>] {
+        &self.shards
+    }
+
+    /// Write-locks every shard holding one of `keys`, each once and always in
+    /// ascending index order. Two multi-key commands locking this way can't end up
+    /// each holding a shard the other is waiting for.
+    pub async fn lock_shards<'k>(&self, keys: impl IntoIterator<Item = &'k str>) -> LockedShards<'_> {
+        let mut indices: Vec<usize> = keys.into_iter().map(|key| self.shard_index(key)).collect();
+        indices.sort_unstable();
+        indices.dedup();
+        let mut guards = Vec::with_capacity(indices.len());
+        for index in indices {
+            guards.push((index, self.shards[index].write().await));
+        }
+        LockedShards { db: self, guards }
+    }
+
+    /// Write-locks the whole keyspace, in the same order as `lock_shards`
+    pub async fn lock_all_shards(&self) -> LockedShards<'_> {
+        let mut guards = Vec::with_capacity(self.shards.len());
+        for (index, shard) in self.shards.iter().enumerate() {
+            guards.push((index, shard.write().await));
+        }
+        LockedShards { db: self, guards }
+    }
+
+    /// Keys removed because their TTL ran out, across all shards
+    pub async fn expired_keys(&self) -> u64 {
+        let mut total = 0;
+        for shard in self.shards.iter() {
+            total += shard.read().await.expired_keys;
+        }
+        total
+    }
+
+    /// One active-expiration tick: checks up to `samples` random keys that have an
+    /// expiry, split evenly over the shards, and removes the expired ones. Only one
+    /// shard is locked at a time. Returns how many keys were removed.
+    pub async fn active_expire_cycle(&self, samples: usize) -> usize {
+        let count = self.shards.len();
+        // Rotate which shards get the samples that don't divide evenly
+        let start = self.expire_cursor.fetch_add(1, Ordering::Relaxed) % count;
+        let mut removed = 0;
+        for offset in 0..count {
+            let budget = samples / count + usize::from(offset < samples % count);
+            if budget == 0 {
+                break;
+            }
+            removed += self.shards[(start + offset) % count].write().await.expire_sample(budget);
+        }
+        removed
+    }
+}
+
+impl Shard {
+    pub fn now(&self) -> Instant {
+        self.clock.now()
+    }
+
+    /// Looks up `key` as stored, even if it has expired and not been reaped yet
+    pub fn get(&self, key: &str) -> Option<&DbValue> {
+        self.data.get(key)
+    }
+
+    pub fn contains_key(&self, key: &str) -> bool {
+        self.data.contains_key(key)
+    }
+
+    /// Every stored key and value, including expired ones not yet reaped
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &DbValue)> {
+        self.data.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    /// Looks up `key`, first removing it if it has expired
+    pub fn get_live(&mut self, key: &str) -> Option<&DbValue> {
+        self.reap_if_expired(key);
+        self.data.get(key)
+    }
+
+    /// Like `get_live`, for changing the value in place. The expiry must only be
+    /// changed through `set_expiry` and `clear_expiry`.
+    pub fn get_live_mut(&mut self, key: &str) -> Option<&mut DbValue> {
+        self.reap_if_expired(key);
+        self.data.get_mut(key)
+    }
+
+    /// Inserts or replaces `value`, tracking `key` for active expiration if it has an expiry
+    pub fn insert(&mut self, key: String, value: DbValue) {
+        if value.expiry.is_some() {
+            self.track_volatile(&key);
+        } else {
+            self.untrack_volatile(&key);
+        }
+        self.data.insert(key, value);
+    }
+
+    pub fn remove(&mut self, key: &str) -> Option<DbValue> {
+        self.untrack_volatile(key);
+        self.data.remove(key)
+    }
+
+    /// Sets the expiry of an existing key. Returns false if there is no such key.
+    pub fn set_expiry(&mut self, key: &str, expiry: Instant) -> bool {
+        let Some(value) = self.data.get_mut(key) else {
+            return false;
+        };
+        value.expiry = Some(expiry);
+        self.track_volatile(key);
+        true
+    }
+
+    /// Makes an existing key persistent. Returns false if it had no expiry.
+    pub fn clear_expiry(&mut self, key: &str) -> bool {
+        let cleared = self.data.get_mut(key).is_some_and(|value| value.expiry.take().is_some());
+        self.untrack_volatile(key);
+        cleared
+    }
+
+    /// Removes every key
+    pub fn clear(&mut self) {
+        self.data.clear();
+        self.volatile.clear();
+        self.volatile_index.clear();
+    }
+
+    fn reap_if_expired(&mut self, key: &str) {
+        let now = self.now();
+        if self.data.get(key).is_some_and(|v| v.is_expired(now)) {
+            self.remove(key);
+            self.expired_keys += 1;
+        }
+    }
+
+    fn track_volatile(&mut self, key: &str) {
+        if !self.volatile_index.contains_key(key) {
+            self.volatile_index.insert(key.to_string(), self.volatile.len());
+            self.volatile.push(key.to_string());
+        }
+    }
+
+    fn untrack_volatile(&mut self, key: &str) {
+        let Some(pos) = self.volatile_index.remove(key) else {
+            return;
+        };
+        self.volatile.swap_remove(pos);
+        if let Some(moved) = self.volatile.get(pos) {
+            self.volatile_index.insert(moved.clone(), pos);
+        }
+    }
+
+    /// Checks up to `samples` random keys that were given an expiry and removes the
+    /// expired ones. Returns how many were removed.
+    fn expire_sample(&mut self, samples: usize) -> usize {
+        let now = self.now();
+        let amount = samples.min(self.volatile.len());
+        let picked: Vec<String> = rand::seq::index::sample(&mut rand::thread_rng(), self.volatile.len(), amount)
+            .into_iter()
+            .map(|i| self.volatile[i].clone())
+            .collect();
+        let mut removed = 0;
+        for key in picked {
+            if self.data.get(&key).is_some_and(|value| value.is_expired(now)) {
+                self.remove(&key);
+                self.expired_keys += 1;
+                removed += 1;
+            }
+        }
+        removed
+    }
 }
 
 impl DbValue {
@@ -39,8 +469,8 @@ impl DbValue {
         Self { data: DataType::List(data), expiry: None }
     }
 
-    pub fn is_expired(&self) -> bool {
-        self.expiry.map_or(false, |exp| Instant::now() > exp)
+    pub fn is_expired(&self, now: Instant) -> bool {
+        self.expiry.is_some_and(|exp| now > exp)
     }
 }
 
@@ -54,14 +484,14 @@ mod tests {
 
         // SET
         {
-            let mut db_lock = db.write().await;
-            db_lock.data.insert("key".to_string(), DbValue::new_string(Bytes::from("value")));
+            let mut shard = db.shard("key").write().await;
+            shard.insert("key".to_string(), DbValue::new_string(Bytes::from("value")));
         }
 
         // GET
         {
-            let db_lock = db.read().await;
-            match db_lock.data.get("key").map(|v| &v.data) {
+            let shard = db.shard("key").read().await;
+            match shard.get("key").map(|v| &v.data) {
                 Some(DataType::String(bs)) => assert_eq!(bs, &Bytes::from("value")),
                 _ => panic!("Expected string"),
             }
@@ -69,14 +499,14 @@ mod tests {
 
         // DEL
         {
-            let mut db_lock = db.write().await;
-            db_lock.data.remove("key");
+            let mut shard = db.shard("key").write().await;
+            shard.remove("key");
         }
 
         // GET after del
         {
-            let db_lock = db.read().await;
-            assert_eq!(db_lock.data.get("key"), None);
+            let shard = db.shard("key").read().await;
+            assert_eq!(shard.get("key"), None);
         }
     }
 
@@ -87,19 +517,229 @@ mod tests {
         val.expiry = Some(Instant::now() - Duration::from_secs(1)); // Expired
 
         {
-            let mut db_lock = db.write().await;
-            db_lock.data.insert("key".to_string(), val);
+            let mut shard = db.shard("key").write().await;
+            shard.insert("key".to_string(), val);
         }
 
         // GET should return null and remove
         {
-            let mut db_lock = db.write().await;
-            if let Some(db_val) = db_lock.data.get("key") {
-                if db_val.is_expired() {
-                    db_lock.data.remove("key");
+            let mut shard = db.shard("key").write().await;
+            let now = shard.now();
+            if let Some(db_val) = shard.get("key") {
+                if db_val.is_expired(now) {
+                    shard.remove("key");
                 }
             }
-            assert_eq!(db_lock.data.get("key"), None);
+            assert_eq!(shard.get("key"), None);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_expiry_with_mock_clock() {
+        let clock = Arc::new(MockClock::new());
+        let db = new_db_with_clock(clock.clone());
+        let mut val = DbValue::new_string(Bytes::from("value"));
+        val.expiry = Some(clock.now() + Duration::from_secs(10));
+        db.shard("key").write().await.insert("key".to_string(), val);
+
+        assert!(!db.shard("key").read().await.data["key"].is_expired(db.now()));
+
+        clock.advance(Duration::from_secs(11));
+        assert!(db.shard("key").read().await.data["key"].is_expired(db.now()));
+    }
+
+    #[tokio::test]
+    async fn test_get_live_reaps_expired() {
+        let clock = Arc::new(MockClock::new());
+        let db = new_db_with_clock(clock.clone());
+        {
+            let mut shard = db.shard("key").write().await;
+            let mut val = DbValue::new_string(Bytes::from("value"));
+            val.expiry = Some(clock.now() + Duration::from_secs(10));
+            shard.insert("key".to_string(), val);
+
+            assert!(shard.get_live("key").is_some());
+            clock.advance(Duration::from_secs(11));
+            assert_eq!(shard.get_live("key"), None);
+            assert!(!shard.contains_key("key"));
+            assert_eq!(shard.expired_keys, 1);
+            assert!(shard.get_live_mut("key").is_none());
+            assert_eq!(shard.expired_keys, 1);
+        }
+        assert_eq!(db.expired_keys().await, 1);
+    }
+
+    /// Runs active-expiration ticks of `samples` keys over `keys` expired keys until
+    /// they are all gone, checking no tick goes over its budget. Returns the tick count.
+    async fn ticks_to_expire(keys: usize, samples: usize) -> usize {
+        let clock = Arc::new(MockClock::new());
+        let db = new_db_with_clock(clock.clone());
+        for i in 0..keys {
+            let key = format!("key{}", i);
+            let mut val = DbValue::new_string(Bytes::from("value"));
+            val.expiry = Some(clock.now() + Duration::from_secs(1));
+            db.shard(&key).write().await.insert(key, val);
+        }
+        clock.advance(Duration::from_secs(2));
+
+        let mut ticks = 0;
+        while db.expired_keys().await < keys as u64 {
+            let removed = db.active_expire_cycle(samples).await;
+            assert!(removed > 0 && removed <= samples, "removed {} with {} samples", removed, samples);
+            ticks += 1;
+        }
+        assert!(db.shards().iter().all(|shard| shard.try_read().unwrap().is_empty()));
+        ticks
+    }
+
+    #[tokio::test]
+    async fn test_active_expire_cycle_sample_size() {
+        let default = ServerConfig::default().active_expire_samples;
+        let default_ticks = ticks_to_expire(1000, default).await;
+        let larger_ticks = ticks_to_expire(1000, default * 10).await;
+        assert!(default_ticks >= 1000 / default, "{} ticks", default_ticks);
+        assert!(larger_ticks < default_ticks, "{} ticks vs {} by default", larger_ticks, default_ticks);
+    }
+
+    #[tokio::test]
+    async fn test_active_expire_cycle_only_samples_keys_with_expiry() {
+        let clock = Arc::new(MockClock::new());
+        let db = new_db_with_clock(clock.clone());
+        for i in 0..1000 {
+            let key = format!("persistent{}", i);
+            db.shard(&key).write().await.insert(key, DbValue::new_string(Bytes::from("value")));
+        }
+        for i in 0..16 {
+            let key = format!("volatile{}", i);
+            let mut val = DbValue::new_string(Bytes::from("value"));
+            val.expiry = Some(clock.now() + Duration::from_secs(1));
+            db.shard(&key).write().await.insert(key, val);
+        }
+        clock.advance(Duration::from_secs(2));
+
+        // 16 samples per shard is enough to see every volatile key in one tick
+        assert_eq!(db.active_expire_cycle(16 * SHARD_COUNT).await, 16);
+        assert_eq!(db.active_expire_cycle(16 * SHARD_COUNT).await, 0);
+    }
+
+    #[tokio::test]
+    async fn test_removing_or_persisting_a_key_untracks_it() {
+        use crate::commands::handle_command;
+        use crate::connection::ConnectionState;
+        use crate::resp::Value;
+
+        let db = new_db();
+        let run = |args: &[&str]| {
+            let cmd: Vec<Value> = args.iter().map(|arg| Value::BulkString(Bytes::from(arg.to_string()))).collect();
+            let db = db.clone();
+            async move { handle_command(&db, &mut ConnectionState::new(), &cmd).await }
+        };
+        let volatile = || async { db.shard("key").read().await.volatile.clone() };
+
+        for unset in [&["DEL", "key"][..], &["PERSIST", "key"], &["SET", "key", "v"], &["GETDEL", "key"]] {
+            run(&["SET", "key", "v", "EX", "100"]).await;
+            assert_eq!(volatile().await, vec!["key".to_string()]);
+            run(unset).await;
+            assert!(volatile().await.is_empty(), "{:?}", unset);
+            assert!(db.shard("key").read().await.volatile_index.is_empty());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_active_expire_cycle_locks_one_shard_at_a_time() {
+        let db = new_db();
+        // A fresh database starts its first tick at shard 0, so the last shard is
+        // reached after every other one has been visited and released
+        let held = db.shards()[SHARD_COUNT - 1].write().await;
+        let db_clone = db.clone();
+        let cycle = tokio::spawn(async move { db_clone.active_expire_cycle(20 * SHARD_COUNT).await });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let first = tokio::time::timeout(Duration::from_secs(1), db.shards()[0].write())
+            .await
+            .expect("active expiration held on to a shard it was done with");
+        drop(first);
+        assert!(!cycle.is_finished());
+        drop(held);
+        assert_eq!(cycle.await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_keys_spread_over_shards() {
+        let db = new_db();
+        let mut used = std::collections::HashSet::new();
+        for i in 0..1000 {
+            used.insert(db.shard_index(&format!("key{}", i)));
+        }
+        assert_eq!(used.len(), SHARD_COUNT);
+        assert!(std::ptr::eq(db.shard("key1"), db.shard("key1")));
+    }
+
+    #[tokio::test]
+    async fn test_lock_shards_dedups_and_orders() {
+        let db = new_db();
+        let keys: Vec<String> = (0..100).map(|i| format!("key{}", i)).collect();
+        let mut locked = db.lock_shards(keys.iter().map(String::as_str).chain(["key1", "key1"])).await;
+        let indices: Vec<usize> = locked.guards.iter().map(|(i, _)| *i).collect();
+        assert!(indices.windows(2).all(|w| w[0] < w[1]));
+
+        for key in &keys {
+            locked.get_mut(key).insert(key.clone(), DbValue::new_string(Bytes::from("v")));
         }
+        assert_eq!(locked.iter().map(|shard| shard.len()).sum::<usize>(), 100);
+        drop(locked);
+        assert!(db.shard("key42").read().await.contains_key("key42"));
+    }
+
+    #[tokio::test]
+    async fn test_overlapping_multi_key_locks_dont_deadlock() {
+        let db = new_db();
+        let mut tasks = Vec::new();
+        for t in 0..32 {
+            let db = db.clone();
+            tasks.push(tokio::spawn(async move {
+                // Each task wants an overlapping, differently ordered set of keys
+                let keys: Vec<String> = (0..8).map(|i| format!("key{}", (t * 3 + i * 7) % 40)).rev().collect();
+                for _ in 0..50 {
+                    let mut locked = db.lock_shards(keys.iter().map(String::as_str)).await;
+                    for key in &keys {
+                        locked.get_mut(key).insert(key.clone(), DbValue::new_string(Bytes::from("v")));
+                    }
+                    tokio::task::yield_now().await;
+                }
+            }));
+        }
+        tokio::time::timeout(Duration::from_secs(10), async {
+            for task in tasks {
+                task.await.unwrap();
+            }
+        })
+        .await
+        .expect("multi-key locking deadlocked");
+    }
+
+    #[test]
+    fn test_output_buffer_limits() {
+        let now = Instant::now();
+        let limit = OutputBufferLimit { hard: 100, soft: 50, soft_duration: Duration::from_secs(10) };
+
+        let buffer = OutputBuffer::default();
+        assert!(buffer.reserve(60, &limit, now));
+        assert!(!buffer.reserve(60, &limit, now)); // Would go over the hard limit
+        buffer.release(60);
+        assert!(buffer.reserve(60, &limit, now));
+
+        // Over the soft limit is fine for a while, but not for soft_duration
+        let buffer = OutputBuffer::default();
+        assert!(buffer.reserve(60, &limit, now));
+        assert!(buffer.reserve(1, &limit, now + Duration::from_secs(5)));
+        assert!(!buffer.reserve(1, &limit, now + Duration::from_secs(10)));
+
+        // Dropping back under the soft limit resets the clock
+        let buffer = OutputBuffer::default();
+        assert!(buffer.reserve(60, &limit, now));
+        buffer.release(60);
+        assert!(buffer.reserve(1, &limit, now + Duration::from_secs(5)));
+        assert!(buffer.reserve(60, &limit, now + Duration::from_secs(11)));
     }
 }
\ No newline at end of file
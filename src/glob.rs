@@ -0,0 +1,109 @@
+/// Redis-style glob matching, as used by KEYS and SCAN MATCH: `*` matches any run of
+/// bytes, `?` any single byte, `[abc]`/`[a-z]` a byte from the set (`[^...]` negates
+/// it), and `\` makes the next byte literal. Every pattern is valid; a `[` without a
+/// closing `]` takes the rest of the pattern as its set.
+pub fn glob_match(pattern: &[u8], string: &[u8]) -> bool {
+    let (mut p, mut s) = (0, 0);
+    // Where to resume after the last `*` if the rest fails: its pattern position
+    // and the string position it has swallowed up to
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while s < string.len() {
+        let step = match pattern.get(p) {
+            Some(b'*') => {
+                backtrack = Some((p, s));
+                p += 1;
+                continue;
+            }
+            Some(b'?') => Some(p + 1),
+            Some(b'[') => match_class(pattern, p, string[s]),
+            Some(b'\\') if p + 1 < pattern.len() => (pattern[p + 1] == string[s]).then_some(p + 2),
+            Some(&c) => (c == string[s]).then_some(p + 1),
+            None => None,
+        };
+        match (step, backtrack) {
+            (Some(next), _) => {
+                p = next;
+                s += 1;
+            }
+            // Let the last `*` swallow one more byte and try again
+            (None, Some((star, swallowed))) => {
+                backtrack = Some((star, swallowed + 1));
+                p = star + 1;
+                s = swallowed + 1;
+            }
+            (None, None) => return false,
+        }
+    }
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+/// Matches `byte` against the class starting at `pattern[start] == b'['` and returns
+/// the pattern position just past the class if it matched
+fn match_class(pattern: &[u8], start: usize, byte: u8) -> Option<usize> {
+    let mut p = start + 1;
+    let negate = pattern.get(p) == Some(&b'^');
+    if negate {
+        p += 1;
+    }
+    let mut matched = false;
+    while p < pattern.len() && pattern[p] != b']' {
+        if pattern[p] == b'\\' && p + 1 < pattern.len() {
+            matched |= pattern[p + 1] == byte;
+            p += 2;
+        } else if p + 2 < pattern.len() && pattern[p + 1] == b'-' && pattern[p + 2] != b']' {
+            let (lo, hi) = (pattern[p].min(pattern[p + 2]), pattern[p].max(pattern[p + 2]));
+            matched |= (lo..=hi).contains(&byte);
+            p += 3;
+        } else {
+            matched |= pattern[p] == byte;
+            p += 1;
+        }
+    }
+    // Step over the closing `]` if there is one
+    (matched != negate).then_some((p + 1).min(pattern.len()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(pattern: &str, string: &str) -> bool {
+        glob_match(pattern.as_bytes(), string.as_bytes())
+    }
+
+    #[test]
+    fn test_star_and_question_mark() {
+        assert!(matches("*", ""));
+        assert!(matches("*", "anything"));
+        assert!(matches("user:*", "user:42"));
+        assert!(!matches("user:*", "session:42"));
+        assert!(matches("*:name", "user:42:name"));
+        assert!(matches("a*b*c", "aXXbYYbc"));
+        assert!(!matches("a*b*c", "aXXbYY"));
+        assert!(matches("h?llo", "hello"));
+        assert!(!matches("h?llo", "hllo"));
+        assert!(!matches("key", "key1"));
+    }
+
+    #[test]
+    fn test_character_classes() {
+        assert!(matches("h[ae]llo", "hallo"));
+        assert!(!matches("h[ae]llo", "hillo"));
+        assert!(matches("h[^e]llo", "hallo"));
+        assert!(!matches("h[^e]llo", "hello"));
+        assert!(matches("key[0-9]", "key7"));
+        assert!(matches("key[9-0]", "key7"));
+        assert!(!matches("key[0-9]", "keyx"));
+        assert!(matches("[a-]", "-"));
+        assert!(matches("[ab", "b"));
+    }
+
+    #[test]
+    fn test_escapes() {
+        assert!(matches("what\\?", "what?"));
+        assert!(!matches("what\\?", "whats"));
+        assert!(matches("\\*star", "*star"));
+        assert!(matches("[\\]]", "]"));
+    }
+}
\ No newline at end of file
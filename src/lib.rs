@@ -1,3 +1,6 @@
 pub mod resp;
 pub mod db;
-pub mod commands;
\ No newline at end of file
+pub mod commands;
+pub mod config;
+pub mod connection;
+pub mod glob;
\ No newline at end of file
@@ -1,91 +1,376 @@
+use std::future::Future;
 use std::io;
-use tokio::net::{TcpListener, TcpStream};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::net::{TcpListener, TcpSocket, TcpStream};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
-use tokio::time::{interval, Duration};
+use tokio::sync::mpsc;
+use tokio::task::JoinSet;
+use tokio::time::{interval, Duration, Instant};
 use bytes::{Bytes, BytesMut};
-use rand::seq::SliceRandom;
-use redust::resp::{parse_value, Value, serialize_value};
-use redust::db::new_db;
-use redust::commands::{handle_command, CommandResult};
+use redust::resp::{parse_command, ParseError, Value, serialize_value};
+use redust::db::{new_db_with_config, Db, OutputBuffer, PubSubMessage, Subscription};
+use redust::commands::{handle_command, subscribed_context_error, wait_if_paused, wrong_arity, CommandResult};
+use redust::config::ServerConfig;
+use redust::connection::ConnectionState;
 
 #[tokio::main]
 async fn main() -> io::Result<()> {
-    let listener = TcpListener::bind("127.0.0.1:6379").await?;
-    let db = new_db();
-    println!("Server listening on 127.0.0.1:6379");
+    let config = ServerConfig::from_args(std::env::args().skip(1))
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let listeners = bind(&config).await?;
+    let config = Arc::new(config);
+    let db = new_db_with_config(&config);
+    for listener in &listeners {
+        println!("Server listening on {}", listener.local_addr()?);
+    }
 
     // Spawn active expiration task
     let db_clone = db.clone();
+    let expire_config = config.clone();
     tokio::spawn(async move {
-        active_expiration(db_clone).await;
+        active_expiration(db_clone, expire_config).await;
     });
 
-    loop {
-        let (socket, _) = listener.accept().await?;
-        let db_clone = db.clone();
-        tokio::spawn(async move {
-            handle_connection(socket, db_clone).await;
+    serve(listeners, db, config, async {
+        let _ = tokio::signal::ctrl_c().await;
+    })
+    .await
+}
+
+/// How long in-flight connections get to finish once shutdown starts
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(1);
+
+/// Accepts connections on every listener until `shutdown` resolves, then gives the
+/// open connections a short grace period before dropping them
+async fn serve(
+    listeners: Vec<TcpListener>,
+    db: Db,
+    config: Arc<ServerConfig>,
+    shutdown: impl Future<Output = ()>,
+) -> io::Result<()> {
+    // One accept loop per listener, all handing their sockets to the loop below
+    let (accepted_tx, mut accepted_rx) = mpsc::unbounded_channel();
+    let mut acceptors = JoinSet::new();
+    for listener in listeners {
+        let accepted_tx = accepted_tx.clone();
+        acceptors.spawn(async move {
+            loop {
+                let accepted = listener.accept().await.map(|(socket, _)| socket);
+                let failed = accepted.is_err();
+                if accepted_tx.send(accepted).is_err() || failed {
+                    return;
+                }
+            }
         });
     }
+    drop(accepted_tx);
+
+    let mut connections = JoinSet::new();
+    tokio::pin!(shutdown);
+    loop {
+        tokio::select! {
+            Some(accepted) = accepted_rx.recv() => {
+                let socket = accepted?;
+                let db_clone = db.clone();
+                let config = config.clone();
+                connections.spawn(async move {
+                    handle_connection(socket, db_clone, config).await;
+                });
+                // Reap finished connections so the set doesn't grow forever
+                while connections.try_join_next().is_some() {}
+            }
+            _ = &mut shutdown => break,
+        }
+    }
+
+    println!("Shutting down");
+    acceptors.shutdown().await;
+    let _ = tokio::time::timeout(SHUTDOWN_GRACE, async {
+        while connections.join_next().await.is_some() {}
+    })
+    .await;
+    Ok(())
 }
 
-async fn handle_subscribe_mode(mut socket: TcpStream, db: &redust::db::Db, channel: String) {
-    let (tx, mut rx) = tokio::sync::mpsc::channel(100);
-    {
-        let mut db_lock = db.write().await;
-        db_lock.channels.entry(channel.clone()).or_insert_with(Vec::new).push(tx);
+/// Resolves each configured bind address and listens on the first address it resolves to
+async fn bind(config: &ServerConfig) -> io::Result<Vec<TcpListener>> {
+    let mut listeners = Vec::new();
+    for bind_addr in config.bind_addrs() {
+        let addr = tokio::net::lookup_host(&bind_addr)
+            .await?
+            .next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::AddrNotAvailable, bind_addr.clone()))?;
+        listeners.push(bind_listener(addr, config.tcp_backlog)?);
     }
+    Ok(listeners)
+}
 
-    // Send subscribe confirmation
-    let response = serialize_value(&Value::Array(vec![
-        Value::BulkString(Bytes::from("subscribe")),
-        Value::BulkString(Bytes::from(channel.clone())),
-        Value::Integer(1),
-    ]));
-    if socket.write(&response).await.is_err() {
-        return;
+/// Binds with an explicit listen backlog instead of the OS default `TcpListener::bind` uses
+fn bind_listener(addr: SocketAddr, backlog: u32) -> io::Result<TcpListener> {
+    let socket = if addr.is_ipv4() { TcpSocket::new_v4()? } else { TcpSocket::new_v6()? };
+    socket.set_reuseaddr(true)?;
+    socket.bind(addr)?;
+    socket.listen(backlog)
+}
+
+/// Channels one subscribed connection is listening on, all fed through a single `tx`
+/// Where a subscribed connection receives messages. The channels it is subscribed
+/// to are kept in its `ConnectionState`.
+struct Subscriber {
+    tx: mpsc::UnboundedSender<PubSubMessage>,
+    buffer: Arc<OutputBuffer>,
+}
+
+/// Sent before closing a connection whose input can't be parsed
+fn protocol_error() -> Value {
+    Value::Error("ERR Protocol error: invalid request".to_string())
+}
+
+/// A RESP2 (un)subscribe confirmation: `[kind, channel, subscriptions remaining]`
+fn subscribe_reply(kind: &str, channel: &[u8], count: i64) -> Value {
+    Value::Array(vec![
+        Value::BulkString(Bytes::copy_from_slice(kind.as_bytes())),
+        Value::BulkString(Bytes::copy_from_slice(channel)),
+        Value::Integer(count),
+    ])
+}
+
+impl Subscriber {
+    async fn subscribe(
+        &self,
+        db: &Db,
+        state: &mut ConnectionState,
+        channels: Vec<Bytes>,
+        sharded: bool,
+    ) -> Vec<Value> {
+        let kind = if sharded { "ssubscribe" } else { "subscribe" };
+        let mut replies = Vec::new();
+        let mut senders = if sharded { &db.shard_channels } else { &db.channels }.write().await;
+        for channel in channels {
+            let subscribed = if sharded { &mut state.shard_channels } else { &mut state.channels };
+            if subscribed.insert(channel.clone()) {
+                senders.entry(channel.clone()).or_insert_with(Vec::new).push(Subscription {
+                    tx: self.tx.clone(),
+                    buffer: self.buffer.clone(),
+                });
+            }
+            replies.push(subscribe_reply(kind, &channel, state.subscription_count(sharded)));
+        }
+        replies
     }
 
-    loop {
-        match rx.recv().await {
-            Some(message) => {
-                let msg = serialize_value(&Value::Array(vec![
-                    Value::BulkString(Bytes::from("message")),
-                    Value::BulkString(Bytes::from(channel.clone())),
-                    Value::BulkString(message),
-                ]));
-                if socket.write(&msg).await.is_err() {
-                    return;
+    /// Unsubscribes from `channels`, or from every channel of that kind when empty
+    async fn unsubscribe(
+        &self,
+        db: &Db,
+        state: &mut ConnectionState,
+        channels: Vec<Bytes>,
+        sharded: bool,
+    ) -> Vec<Value> {
+        let kind = if sharded { "sunsubscribe" } else { "unsubscribe" };
+        let channels = if channels.is_empty() {
+            let subscribed = if sharded { &state.shard_channels } else { &state.channels };
+            subscribed.iter().cloned().collect()
+        } else {
+            channels
+        };
+        if channels.is_empty() {
+            return vec![Value::Array(vec![
+                Value::BulkString(Bytes::from(kind)),
+                Value::Null,
+                Value::Integer(state.subscription_count(sharded)),
+            ])];
+        }
+
+        let mut replies = Vec::new();
+        let mut senders_by_channel = if sharded { &db.shard_channels } else { &db.channels }.write().await;
+        for channel in channels {
+            let subscribed = if sharded { &mut state.shard_channels } else { &mut state.channels };
+            if subscribed.remove(&channel) {
+                if let Some(senders) = senders_by_channel.get_mut(&channel) {
+                    senders.retain(|sub| !sub.tx.same_channel(&self.tx));
+                    if senders.is_empty() {
+                        senders_by_channel.remove(&channel);
+                    }
                 }
             }
-            None => return, // Channel closed
+            replies.push(subscribe_reply(kind, &channel, state.subscription_count(sharded)));
         }
+        replies
+    }
+
+    async fn unsubscribe_all(&self, db: &Db, state: &mut ConnectionState) {
+        self.unsubscribe(db, state, Vec::new(), false).await;
+        self.unsubscribe(db, state, Vec::new(), true).await;
     }
 }
 
-async fn active_expiration(db: redust::db::Db) {
-    let mut interval = interval(Duration::from_millis(100));
+fn arg_bytes(args: &[Value]) -> Vec<Bytes> {
+    args.iter()
+        .filter_map(|arg| match arg {
+            Value::BulkString(bs) => Some(bs.clone()),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Runs a connection in RESP2 subscribed state. Returns true once every subscription
+/// is gone and the connection should go back to normal commands, false to close it.
+async fn handle_subscribe_mode(
+    socket: &mut TcpStream,
+    buf: &mut BytesMut,
+    db: &Db,
+    state: &mut ConnectionState,
+    config: &ServerConfig,
+    channels: Vec<Bytes>,
+    sharded: bool,
+) -> bool {
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    let buffer = Arc::new(OutputBuffer::default());
+    let subscriber = Subscriber { tx, buffer: buffer.clone() };
+
+    // Send subscribe confirmation
+    for reply in subscriber.subscribe(db, state, channels, sharded).await {
+        if socket.write(&serialize_value(&reply)).await.is_err() {
+            subscriber.unsubscribe_all(db, state).await;
+            return false;
+        }
+    }
+
     loop {
-        interval.tick().await;
-        let mut db_lock = db.write().await;
-        let keys: Vec<String> = db_lock.data.keys().cloned().collect();
-        let sample: Vec<_> = keys.choose_multiple(&mut rand::thread_rng(), 20.min(keys.len())).collect();
-        for key in sample {
-            if let Some(val) = db_lock.data.get(key) {
-                if val.is_expired() {
-                    db_lock.data.remove(key);
+        // Handle every complete command already buffered before waiting for more,
+        // including any that came in the same read as the SUBSCRIBE
+        loop {
+            let cmd = match parse_command(buf, config.lenient_newlines) {
+                Ok(Value::Array(cmd)) if !cmd.is_empty() => cmd,
+                Ok(_) => continue, // Not a command
+                Err(ParseError::Incomplete) => break,
+                Err(ParseError::InvalidFormat) => {
+                    let _ = socket.write(&serialize_value(&protocol_error())).await;
+                    subscriber.unsubscribe_all(db, state).await;
+                    return false;
+                }
+            };
+            let replies = match subscribed_command(db, state, &subscriber, cmd).await {
+                Ok(replies) => replies,
+                Err(last_reply) => {
+                    if let Some(reply) = last_reply {
+                        let _ = socket.write(&serialize_value(&reply)).await;
+                    }
+                    subscriber.unsubscribe_all(db, state).await;
+                    return false;
+                }
+            };
+            for reply in replies {
+                if socket.write(&serialize_value(&reply)).await.is_err() {
+                    subscriber.unsubscribe_all(db, state).await;
+                    return false;
                 }
             }
+            if !state.is_subscribed() {
+                // Anything still buffered goes back to the normal command loop
+                return true;
+            }
         }
+
+        tokio::select! {
+            _ = buffer.closed() => {
+                // A publisher found us over the output buffer limit
+                subscriber.unsubscribe_all(db, state).await;
+                return false;
+            }
+            Some(message) = rx.recv() => {
+                buffer.release(message.size());
+                let kind = if message.sharded { "smessage" } else { "message" };
+                let reply = Value::Array(vec![
+                    Value::BulkString(Bytes::from(kind)),
+                    Value::BulkString(message.channel),
+                    Value::BulkString(message.payload),
+                ]);
+                if socket.write(&serialize_value(&reply)).await.is_err() {
+                    subscriber.unsubscribe_all(db, state).await;
+                    return false;
+                }
+            }
+            read = socket.read_buf(buf) => {
+                if !matches!(read, Ok(n) if n > 0) {
+                    subscriber.unsubscribe_all(db, state).await;
+                    return false; // Connection closed
+                }
+            }
+        }
+    }
+}
+
+/// Runs one command received in subscribed state and returns its replies, or
+/// `Err` with an optional last reply when the connection should close
+async fn subscribed_command(
+    db: &Db,
+    state: &mut ConnectionState,
+    subscriber: &Subscriber,
+    cmd: Vec<Value>,
+) -> Result<Vec<Value>, Option<Value>> {
+    if let Some(err) = subscribed_context_error(&cmd) {
+        return Ok(vec![err]);
+    }
+    let name = arg_bytes(&cmd[..1]).pop().unwrap_or_default().to_ascii_uppercase();
+    let name = String::from_utf8_lossy(&name);
+    let args = arg_bytes(&cmd[1..]);
+    let replies = match name.as_ref() {
+        "SUBSCRIBE" | "SSUBSCRIBE" if args.is_empty() => vec![wrong_arity(&name.to_lowercase())],
+        "SUBSCRIBE" => subscriber.subscribe(db, state, args, false).await,
+        "SSUBSCRIBE" => subscriber.subscribe(db, state, args, true).await,
+        "UNSUBSCRIBE" => subscriber.unsubscribe(db, state, args, false).await,
+        "SUNSUBSCRIBE" => subscriber.unsubscribe(db, state, args, true).await,
+        "RESET" if !args.is_empty() => vec![wrong_arity("reset")],
+        // Drops every subscription at once, without the unsubscribe replies
+        "RESET" => {
+            subscriber.unsubscribe_all(db, state).await;
+            match handle_command(db, state, &cmd).await {
+                Some(CommandResult::Value(response)) => vec![response],
+                _ => Vec::new(),
+            }
+        }
+        "PING" => vec![Value::Array(vec![
+            Value::BulkString(Bytes::from("pong")),
+            Value::BulkString(args.into_iter().next().unwrap_or_default()),
+        ])],
+        _ => match handle_command(db, state, &cmd).await {
+            Some(CommandResult::Value(response)) => vec![response],
+            Some(CommandResult::Close(response)) => return Err(response),
+            _ => Vec::new(),
+        },
+    };
+    Ok(replies)
+}
+
+async fn active_expiration(db: Db, config: Arc<ServerConfig>) {
+    let mut interval = interval(config.tick_interval());
+    loop {
+        interval.tick().await;
+        db.active_expire_cycle(config.active_expire_samples).await;
     }
 }
 
-async fn handle_connection(mut socket: TcpStream, db: redust::db::Db) {
+async fn handle_connection(mut socket: TcpStream, db: Db, config: Arc<ServerConfig>) {
     let mut buf = BytesMut::with_capacity(1024);
+    let mut state = ConnectionState::new();
+    // When we started waiting on the rest of a partially received command
+    let mut partial_since: Option<Instant> = None;
 
     loop {
-        // Read data
-        socket.readable().await.unwrap();
+        // Read data, but don't let a stalled partial frame hold the connection forever
+        match partial_since {
+            Some(since) if !config.proto_read_timeout.is_zero() => {
+                let deadline = since + config.proto_read_timeout;
+                match tokio::time::timeout_at(deadline, socket.readable()).await {
+                    Ok(readable) => readable.unwrap(),
+                    Err(_) => return,
+                }
+            }
+            _ => socket.readable().await.unwrap(),
+        }
         match socket.try_read_buf(&mut buf) {
             Ok(0) => return, // Connection closed
             Ok(_) => {},
@@ -96,25 +381,324 @@ async fn handle_connection(mut socket: TcpStream, db: redust::db::Db) {
             }
         }
 
-        // Try to parse
-        match parse_value(&mut buf) {
-            Ok(Value::Array(arr)) => {
-                match handle_command(&db, &arr).await {
-                    Some(CommandResult::Value(response)) => {
-                        let serialized = serialize_value(&response);
-                        if socket.write(&serialized).await.is_err() {
+        // Handle every complete command in the buffer, pipelined ones included
+        loop {
+            let parsed = parse_command(&mut buf, config.lenient_newlines);
+            match parsed {
+                Err(ParseError::Incomplete) if buf.is_empty() => partial_since = None,
+                Err(ParseError::Incomplete) => {
+                    partial_since.get_or_insert_with(Instant::now);
+                }
+                _ => partial_since = None,
+            }
+            match parsed {
+                Ok(Value::Array(arr)) => {
+                    wait_if_paused(&db, &arr).await;
+                    match handle_command(&db, &mut state, &arr).await {
+                        Some(CommandResult::Value(response)) => {
+                            let serialized = serialize_value(&response);
+                            if socket.write(&serialized).await.is_err() {
+                                return;
+                            }
+                        }
+                        Some(CommandResult::Close(response)) => {
+                            if let Some(response) = response {
+                                let _ = socket.write(&serialize_value(&response)).await;
+                            }
                             return;
                         }
+                        Some(CommandResult::Subscribe(channels)) => {
+                            if handle_subscribe_mode(&mut socket, &mut buf, &db, &mut state, &config, channels, false).await {
+                                continue;
+                            }
+                            return;
+                        }
+                        Some(CommandResult::SSubscribe(channels)) => {
+                            if handle_subscribe_mode(&mut socket, &mut buf, &db, &mut state, &config, channels, true).await {
+                                continue;
+                            }
+                            return;
+                        }
+                        None => {} // No reply, e.g. an empty command
                     }
-                    Some(CommandResult::Subscribe(channel)) => {
-                        handle_subscribe_mode(socket, &db, channel).await;
-                        return; // End connection after subscribe
-                    }
-                    None => {}
                 }
+                Ok(_) => {} // Ignore non-array
+                Err(ParseError::Incomplete) => break, // Wait for more data
+                Err(ParseError::InvalidFormat) => {
+                    // There's no telling where the next frame starts, so give up on the connection
+                    let _ = socket.write(&serialize_value(&protocol_error())).await;
+                    return;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use redust::resp::parse_value;
+    use redust::db::new_db;
+
+    #[test]
+    fn test_subscribe_reply() {
+        for (kind, count) in [("subscribe", 1), ("unsubscribe", 0), ("ssubscribe", 2), ("sunsubscribe", 1)] {
+            assert_eq!(
+                serialize_value(&subscribe_reply(kind, b"news", count)),
+                format!("*3\r\n${}\r\n{}\r\n$4\r\nnews\r\n:{}\r\n", kind.len(), kind, count).into_bytes()
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_bind_listener_with_backlog() {
+        let listener = bind_listener("127.0.0.1:0".parse().unwrap(), 16).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let client = TcpStream::connect(addr).await;
+        assert!(client.is_ok());
+        assert!(listener.accept().await.is_ok());
+    }
+
+    async fn start_server() -> TcpStream {
+        start_server_with_config(ServerConfig::default()).await
+    }
+
+    async fn start_server_with_config(config: ServerConfig) -> TcpStream {
+        let listener = bind_listener("127.0.0.1:0".parse().unwrap(), 16).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let db = new_db();
+        tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            handle_connection(socket, db, Arc::new(config)).await;
+        });
+        TcpStream::connect(addr).await.unwrap()
+    }
+
+    async fn send_command(stream: &mut TcpStream, parts: &[&str]) {
+        let cmd = Value::Array(parts.iter().map(|p| Value::BulkString(Bytes::from(p.to_string()))).collect());
+        stream.write_all(&serialize_value(&cmd)).await.unwrap();
+    }
+
+    async fn read_reply(stream: &mut TcpStream, buf: &mut BytesMut) -> Value {
+        loop {
+            if let Ok(value) = parse_value(buf) {
+                return value;
             }
-            Ok(_) => {} // Ignore non-array
-            Err(_) => {} // Incomplete, wait for more data
+            let n = tokio::time::timeout(Duration::from_secs(1), stream.read_buf(buf))
+                .await
+                .expect("timed out waiting for a reply")
+                .unwrap();
+            assert!(n > 0, "connection closed");
         }
     }
+
+    #[tokio::test]
+    async fn test_subscribed_connection_rejects_get() {
+        let mut stream = start_server().await;
+        let mut buf = BytesMut::new();
+
+        send_command(&mut stream, &["SUBSCRIBE", "news"]).await;
+        assert_eq!(read_reply(&mut stream, &mut buf).await, Value::Array(vec![
+            Value::BulkString(Bytes::from("subscribe")),
+            Value::BulkString(Bytes::from("news")),
+            Value::Integer(1),
+        ]));
+
+        send_command(&mut stream, &["GET", "key"]).await;
+        assert_eq!(read_reply(&mut stream, &mut buf).await, Value::Error(
+            "ERR Can't execute 'get': only (P|S)SUBSCRIBE / (P|S)UNSUBSCRIBE / PING / QUIT / RESET are allowed in this context".to_string()
+        ));
+
+        // Dropping the last subscription returns to normal commands
+        send_command(&mut stream, &["UNSUBSCRIBE"]).await;
+        assert_eq!(read_reply(&mut stream, &mut buf).await, Value::Array(vec![
+            Value::BulkString(Bytes::from("unsubscribe")),
+            Value::BulkString(Bytes::from("news")),
+            Value::Integer(0),
+        ]));
+        send_command(&mut stream, &["PING"]).await;
+        assert_eq!(read_reply(&mut stream, &mut buf).await, Value::SimpleString("PONG".to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_reset_leaves_subscribed_state() {
+        let mut stream = start_server().await;
+        let mut buf = BytesMut::new();
+
+        send_command(&mut stream, &["SUBSCRIBE", "news", "sports"]).await;
+        read_reply(&mut stream, &mut buf).await;
+        read_reply(&mut stream, &mut buf).await;
+
+        send_command(&mut stream, &["RESET"]).await;
+        assert_eq!(read_reply(&mut stream, &mut buf).await, Value::SimpleString("RESET".to_string()));
+
+        send_command(&mut stream, &["GET", "key"]).await;
+        assert_eq!(read_reply(&mut stream, &mut buf).await, Value::Null);
+        send_command(&mut stream, &["PUBLISH", "news", "hello"]).await;
+        assert_eq!(read_reply(&mut stream, &mut buf).await, Value::Integer(0));
+    }
+
+    #[tokio::test]
+    async fn test_quit_closes_connection() {
+        let mut stream = start_server().await;
+        let mut buf = BytesMut::new();
+
+        send_command(&mut stream, &["QUIT"]).await;
+        assert_eq!(read_reply(&mut stream, &mut buf).await, Value::SimpleString("OK".to_string()));
+        let n = tokio::time::timeout(Duration::from_secs(1), stream.read_buf(&mut buf))
+            .await
+            .expect("server should close the connection")
+            .unwrap();
+        assert_eq!(n, 0);
+    }
+
+    #[tokio::test]
+    async fn test_empty_command_keeps_connection_open() {
+        let mut stream = start_server().await;
+        let mut buf = BytesMut::new();
+
+        stream.write_all(b"*0\r\n").await.unwrap();
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        stream.write_all(b"\r\n").await.unwrap();
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        send_command(&mut stream, &["PING"]).await;
+        assert_eq!(read_reply(&mut stream, &mut buf).await, Value::SimpleString("PONG".to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_stalled_partial_command_is_closed() {
+        let config = ServerConfig { proto_read_timeout: Duration::from_millis(100), ..ServerConfig::default() };
+        let mut stream = start_server_with_config(config).await;
+        let mut buf = BytesMut::new();
+
+        stream.write_all(b"*3\r\n$3\r\nSET\r\n").await.unwrap();
+        let n = tokio::time::timeout(Duration::from_secs(2), stream.read_buf(&mut buf))
+            .await
+            .expect("server should drop the stalled connection")
+            .unwrap();
+        assert_eq!(n, 0);
+    }
+
+    #[tokio::test]
+    async fn test_garbage_after_command_closes_connection() {
+        let mut stream = start_server().await;
+        let mut buf = BytesMut::new();
+
+        stream.write_all(b"*1\r\n$4\r\nPING\r\n").await.unwrap();
+        assert_eq!(read_reply(&mut stream, &mut buf).await, Value::SimpleString("PONG".to_string()));
+
+        stream.write_all(b"*x\r\n").await.unwrap();
+        assert_eq!(read_reply(&mut stream, &mut buf).await, protocol_error());
+        let n = tokio::time::timeout(Duration::from_secs(1), stream.read_buf(&mut buf))
+            .await
+            .expect("server should close the connection")
+            .unwrap();
+        assert_eq!(n, 0);
+    }
+
+    #[tokio::test]
+    async fn test_serve_stops_on_shutdown() {
+        let listener = bind_listener("127.0.0.1:0".parse().unwrap(), 16).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (shutdown_tx, shutdown_rx) = tokio::sync::oneshot::channel::<()>();
+        let server = tokio::spawn(serve(vec![listener], new_db(), Arc::new(ServerConfig::default()), async {
+            let _ = shutdown_rx.await;
+        }));
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let mut buf = BytesMut::new();
+        stream.write_all(b"PING\r\n").await.unwrap();
+        assert_eq!(read_reply(&mut stream, &mut buf).await, Value::SimpleString("PONG".to_string()));
+
+        shutdown_tx.send(()).unwrap();
+        let result = tokio::time::timeout(SHUTDOWN_GRACE * 3, server)
+            .await
+            .expect("server should stop after the grace period");
+        assert!(result.unwrap().is_ok());
+        assert!(TcpStream::connect(addr).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_bind_from_config() {
+        let config = ServerConfig::from_args(["--port".to_string(), "0".to_string()]).unwrap();
+        let listeners = bind(&config).await.unwrap();
+        assert_eq!(listeners.len(), 1);
+        let addr = listeners[0].local_addr().unwrap();
+        assert!(addr.ip().is_loopback());
+        assert_ne!(addr.port(), 0);
+        tokio::spawn(serve(listeners, new_db(), Arc::new(config), std::future::pending()));
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let mut buf = BytesMut::new();
+        send_command(&mut stream, &["PING"]).await;
+        assert_eq!(read_reply(&mut stream, &mut buf).await, Value::SimpleString("PONG".to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_bind_several_addresses() {
+        let config = ServerConfig::from_args(["--bind".to_string(), "127.0.0.1:0,127.0.0.1:0".to_string()]).unwrap();
+        let listeners = bind(&config).await.unwrap();
+        let addrs: Vec<SocketAddr> = listeners.iter().map(|l| l.local_addr().unwrap()).collect();
+        assert_eq!(addrs.len(), 2);
+        assert_ne!(addrs[0], addrs[1]);
+        tokio::spawn(serve(listeners, new_db(), Arc::new(config), std::future::pending()));
+
+        let mut first = TcpStream::connect(addrs[0]).await.unwrap();
+        let mut second = TcpStream::connect(addrs[1]).await.unwrap();
+        let (mut first_buf, mut second_buf) = (BytesMut::new(), BytesMut::new());
+        send_command(&mut first, &["PING"]).await;
+        assert_eq!(read_reply(&mut first, &mut first_buf).await, Value::SimpleString("PONG".to_string()));
+        send_command(&mut second, &["PING"]).await;
+        assert_eq!(read_reply(&mut second, &mut second_buf).await, Value::SimpleString("PONG".to_string()));
+
+        // Both listeners share one keyspace
+        send_command(&mut first, &["SET", "shared", "yes"]).await;
+        assert_eq!(read_reply(&mut first, &mut first_buf).await, Value::SimpleString("OK".to_string()));
+        send_command(&mut second, &["GET", "shared"]).await;
+        assert_eq!(read_reply(&mut second, &mut second_buf).await, Value::BulkString(Bytes::from("yes")));
+    }
+
+    #[tokio::test]
+    async fn test_pipelined_commands() {
+        let mut stream = start_server().await;
+        let mut buf = BytesMut::new();
+        stream
+            .write_all(b"*3\r\n$3\r\nSET\r\n$1\r\na\r\n$1\r\n1\r\n*3\r\n$3\r\nSET\r\n$1\r\nb\r\n$1\r\n2\r\nGET b\r\n")
+            .await
+            .unwrap();
+        assert_eq!(read_reply(&mut stream, &mut buf).await, Value::SimpleString("OK".to_string()));
+        assert_eq!(read_reply(&mut stream, &mut buf).await, Value::SimpleString("OK".to_string()));
+        assert_eq!(read_reply(&mut stream, &mut buf).await, Value::BulkString(Bytes::from("2")));
+    }
+
+    #[tokio::test]
+    async fn test_commands_pipelined_while_subscribed() {
+        let mut stream = start_server().await;
+        let mut buf = BytesMut::new();
+        // PING arrives in the same write as the SUBSCRIBE that switches modes
+        stream.write_all(b"SUBSCRIBE news\r\nPING\r\n").await.unwrap();
+        assert_eq!(read_reply(&mut stream, &mut buf).await, Value::Array(vec![
+            Value::BulkString(Bytes::from("subscribe")),
+            Value::BulkString(Bytes::from("news")),
+            Value::Integer(1),
+        ]));
+        assert_eq!(read_reply(&mut stream, &mut buf).await, Value::Array(vec![
+            Value::BulkString(Bytes::from("pong")),
+            Value::BulkString(Bytes::new()),
+        ]));
+
+        // Several commands in one write while already subscribed, the last of them
+        // back in normal mode
+        stream.write_all(b"PING a\r\nUNSUBSCRIBE\r\nPING\r\n").await.unwrap();
+        assert_eq!(read_reply(&mut stream, &mut buf).await, Value::Array(vec![
+            Value::BulkString(Bytes::from("pong")),
+            Value::BulkString(Bytes::from("a")),
+        ]));
+        assert_eq!(read_reply(&mut stream, &mut buf).await, Value::Array(vec![
+            Value::BulkString(Bytes::from("unsubscribe")),
+            Value::BulkString(Bytes::from("news")),
+            Value::Integer(0),
+        ]));
+        assert_eq!(read_reply(&mut stream, &mut buf).await, Value::SimpleString("PONG".to_string()));
+    }
 }
\ No newline at end of file
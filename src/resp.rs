@@ -1,4 +1,4 @@
-use bytes::{Buf, Bytes};
+use bytes::{Buf, Bytes, BytesMut};
 use thiserror::Error;
 
 #[derive(Error, Debug)]
@@ -17,10 +17,193 @@ pub enum Value {
     Integer(i64),
     BulkString(Bytes),
     Array(Vec<Value>),
+    /// RESP2 null. Both the null bulk string (`$-1`) and the null array (`*-1`) parse
+    /// to this, and it is always written back as `$-1`.
     Null,
+    /// RESP3 `,` double
+    Double(f64),
+    /// RESP3 `(` big number, kept as its decimal digits
+    BigNumber(String),
 }
 
-pub fn parse_value(buf: &mut impl Buf) -> Result<Value, ParseError> {
+/// Parses one client command: a RESP array, or an inline command (a plain line of
+/// whitespace-separated words). An empty inline line is an empty array. With
+/// `lenient_newlines` an inline line may end in a bare `\n`; RESP framing is always strict.
+/// Like `parse_value`, nothing is consumed until a whole command has arrived.
+pub fn parse_command(buf: &mut BytesMut, lenient_newlines: bool) -> Result<Value, ParseError> {
+    let len = match buf.first() {
+        None => return Err(ParseError::Incomplete),
+        Some(b'*') => frame_end(buf, 0)?,
+        Some(_) => line_end(buf, 0, lenient_newlines)?,
+    };
+    parse_command_frame(&mut buf.split_to(len).freeze(), lenient_newlines)
+}
+
+/// Parses one RESP value from the front of `buf`. On `Incomplete` the buffer is left
+/// as it was, so the caller can append the next read and try again.
+pub fn parse_value(buf: &mut BytesMut) -> Result<Value, ParseError> {
+    let len = frame_end(buf, 0)?;
+    parse_value_frame(&mut buf.split_to(len).freeze())
+}
+
+/// Finds where the RESP frame starting at `pos` ends without building any values, so an
+/// unfinished frame costs a scan rather than a parse. Once this succeeds the frame is
+/// split off the buffer and its bulk strings become slices of it instead of copies.
+fn frame_end(buf: &[u8], pos: usize) -> Result<usize, ParseError> {
+    let Some(&kind) = buf.get(pos) else {
+        return Err(ParseError::Incomplete);
+    };
+    let end = line_end(buf, pos + 1, false)?;
+    match kind {
+        b'+' | b'-' | b':' | b',' | b'(' => Ok(end),
+        b'$' => match frame_len(&buf[pos + 1..end - 2])? {
+            None => Ok(end),
+            Some(len) => {
+                let data_end = end.checked_add(len).and_then(|e| e.checked_add(2));
+                match data_end {
+                    Some(data_end) if data_end <= buf.len() => Ok(data_end),
+                    Some(_) => Err(ParseError::Incomplete),
+                    None => Err(ParseError::InvalidFormat),
+                }
+            }
+        },
+        b'*' => {
+            let mut end = end;
+            for _ in 0..frame_len(&buf[pos + 1..end - 2])?.unwrap_or(0) {
+                end = frame_end(buf, end)?;
+            }
+            Ok(end)
+        }
+        _ => Err(ParseError::InvalidFormat),
+    }
+}
+
+/// Reads a bulk string or array header length, where `-1` means null
+fn frame_len(header: &[u8]) -> Result<Option<usize>, ParseError> {
+    if header == b"-1" {
+        return Ok(None);
+    }
+    std::str::from_utf8(header)
+        .ok()
+        .and_then(|len| len.parse().ok())
+        .map(Some)
+        .ok_or(ParseError::InvalidFormat)
+}
+
+/// Index just past the line starting at `pos`, with the same terminator rules as
+/// `read_raw_line`
+fn line_end(buf: &[u8], pos: usize, lenient: bool) -> Result<usize, ParseError> {
+    let Some(offset) = buf[pos..].iter().position(|&b| b == b'\r' || b == b'\n') else {
+        return Err(ParseError::Incomplete);
+    };
+    let at = pos + offset;
+    match (buf[at], buf.get(at + 1)) {
+        (b'\r', Some(b'\n')) => Ok(at + 2),
+        (b'\r', None) => Err(ParseError::Incomplete),
+        (b'\n', _) if lenient => Ok(at + 1),
+        _ => Err(ParseError::InvalidFormat),
+    }
+}
+
+fn parse_command_frame(buf: &mut impl Buf, lenient_newlines: bool) -> Result<Value, ParseError> {
+    if !buf.has_remaining() {
+        return Err(ParseError::Incomplete);
+    }
+    if buf.chunk()[0] == b'*' {
+        return parse_value_frame(buf);
+    }
+
+    let line = read_raw_line(buf, lenient_newlines)?;
+    let args = split_inline_args(&line)?.into_iter().map(Value::BulkString).collect();
+    Ok(Value::Array(args))
+}
+
+/// Splits an inline command line into arguments the way redis-cli's `sdssplitargs`
+/// does: words are separated by whitespace, `"..."` understands `\xHH`, `\n`, `\r`,
+/// `\t`, `\b` and `\a` escapes, and `'...'` is literal apart from `\'`. A closing
+/// quote must end the argument, and an unterminated quote is an error.
+pub fn split_inline_args(line: &[u8]) -> Result<Vec<Bytes>, ParseError> {
+    let mut args = Vec::new();
+    let mut i = 0;
+    loop {
+        while i < line.len() && line[i].is_ascii_whitespace() {
+            i += 1;
+        }
+        if i == line.len() {
+            return Ok(args);
+        }
+
+        let mut arg = Vec::new();
+        let (mut in_double, mut in_single) = (false, false);
+        loop {
+            let Some(&byte) = line.get(i) else {
+                if in_double || in_single {
+                    return Err(ParseError::InvalidFormat); // Unbalanced quotes
+                }
+                break;
+            };
+            if in_double {
+                match byte {
+                    b'\\' if line.get(i + 1) == Some(&b'x')
+                        && line.len() > i + 3
+                        && line[i + 2].is_ascii_hexdigit()
+                        && line[i + 3].is_ascii_hexdigit() =>
+                    {
+                        let hex = std::str::from_utf8(&line[i + 2..i + 4]).unwrap();
+                        arg.push(u8::from_str_radix(hex, 16).unwrap());
+                        i += 3;
+                    }
+                    b'\\' if i + 1 < line.len() => {
+                        i += 1;
+                        arg.push(match line[i] {
+                            b'n' => b'\n',
+                            b'r' => b'\r',
+                            b't' => b'\t',
+                            b'b' => 0x08,
+                            b'a' => 0x07,
+                            other => other,
+                        });
+                    }
+                    b'"' => {
+                        // The closing quote must be followed by whitespace or the end
+                        if line.get(i + 1).is_some_and(|b| !b.is_ascii_whitespace()) {
+                            return Err(ParseError::InvalidFormat);
+                        }
+                        i += 1;
+                        break;
+                    }
+                    other => arg.push(other),
+                }
+            } else if in_single {
+                match byte {
+                    b'\\' if line.get(i + 1) == Some(&b'\'') => {
+                        arg.push(b'\'');
+                        i += 1;
+                    }
+                    b'\'' => {
+                        if line.get(i + 1).is_some_and(|b| !b.is_ascii_whitespace()) {
+                            return Err(ParseError::InvalidFormat);
+                        }
+                        i += 1;
+                        break;
+                    }
+                    other => arg.push(other),
+                }
+            } else {
+                match byte {
+                    b if b.is_ascii_whitespace() => break,
+                    b'"' => in_double = true,
+                    b'\'' => in_single = true,
+                    other => arg.push(other),
+                }
+            }
+            i += 1;
+        }
+        args.push(Bytes::from(arg));
+    }
+}
+
+fn parse_value_frame(buf: &mut impl Buf) -> Result<Value, ParseError> {
     if !buf.has_remaining() {
         return Err(ParseError::Incomplete);
     }
@@ -31,6 +214,8 @@ pub fn parse_value(buf: &mut impl Buf) -> Result<Value, ParseError> {
         b':' => parse_integer(buf),
         b'$' => parse_bulk_string(buf),
         b'*' => parse_array(buf),
+        b',' => parse_double(buf),
+        b'(' => parse_big_number(buf),
         _ => Err(ParseError::InvalidFormat),
     }
 }
@@ -51,6 +236,21 @@ fn parse_integer(buf: &mut impl Buf) -> Result<Value, ParseError> {
     Ok(Value::Integer(num))
 }
 
+fn parse_double(buf: &mut impl Buf) -> Result<Value, ParseError> {
+    let line = read_line(buf)?;
+    let num: f64 = line.parse().map_err(|_| ParseError::InvalidFormat)?;
+    Ok(Value::Double(num))
+}
+
+fn parse_big_number(buf: &mut impl Buf) -> Result<Value, ParseError> {
+    let line = read_line(buf)?;
+    let digits = line.strip_prefix(['-', '+']).unwrap_or(&line);
+    if digits.is_empty() || !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return Err(ParseError::InvalidFormat);
+    }
+    Ok(Value::BigNumber(line))
+}
+
 fn parse_bulk_string(buf: &mut impl Buf) -> Result<Value, ParseError> {
     let len_line = read_line(buf)?;
     if len_line == "-1" {
@@ -60,6 +260,7 @@ fn parse_bulk_string(buf: &mut impl Buf) -> Result<Value, ParseError> {
     if buf.remaining() < len + 2 {
         return Err(ParseError::Incomplete);
     }
+    // A slice of the frame when `buf` is `Bytes`, not a copy
     let data = buf.copy_to_bytes(len);
     // consume \r\n
     buf.advance(2);
@@ -74,29 +275,42 @@ fn parse_array(buf: &mut impl Buf) -> Result<Value, ParseError> {
     let len: usize = len_line.parse().map_err(|_| ParseError::InvalidFormat)?;
     let mut array = Vec::with_capacity(len);
     for _ in 0..len {
-        array.push(parse_value(buf)?);
+        array.push(parse_value_frame(buf)?);
     }
     Ok(Value::Array(array))
 }
 
 fn read_line(buf: &mut impl Buf) -> Result<String, ParseError> {
+    let line = read_raw_line(buf, false)?;
+    String::from_utf8(line).map_err(|_| ParseError::InvalidFormat)
+}
+
+/// Reads up to and including the line terminator, which must be `\r\n` unless
+/// `lenient` also allows a bare `\n`
+fn read_raw_line(buf: &mut impl Buf, lenient: bool) -> Result<Vec<u8>, ParseError> {
     let mut line = Vec::new();
     loop {
         if !buf.has_remaining() {
             return Err(ParseError::Incomplete);
         }
-        let byte = buf.get_u8();
-        if byte == b'\r' {
-            if !buf.has_remaining() || buf.get_u8() != b'\n' {
-                return Err(ParseError::InvalidFormat);
+        match buf.get_u8() {
+            b'\r' => {
+                if !buf.has_remaining() {
+                    return Err(ParseError::Incomplete);
+                }
+                if buf.get_u8() != b'\n' {
+                    return Err(ParseError::InvalidFormat);
+                }
+                return Ok(line);
             }
-            break;
+            b'\n' if lenient => return Ok(line),
+            b'\n' => return Err(ParseError::InvalidFormat),
+            byte => line.push(byte),
         }
-        line.push(byte);
     }
-    String::from_utf8(line).map_err(|_| ParseError::InvalidFormat)
 }
 
+/// Encodes a value in its wire form; `parse_value` reads the result back as the same value
 pub fn serialize_value(value: &Value) -> Vec<u8> {
     match value {
         Value::SimpleString(s) => format!("+{}\r\n", s).into_bytes(),
@@ -117,6 +331,17 @@ pub fn serialize_value(value: &Value) -> Vec<u8> {
             res
         }
         Value::Null => b"$-1\r\n".to_vec(),
+        Value::Double(d) => format!(",{}\r\n", format_double(*d)).into_bytes(),
+        Value::BigNumber(n) => format!("({}\r\n", n).into_bytes(),
+    }
+}
+
+/// Text form of a double, spelling the special values the way RESP3 does
+pub fn format_double(d: f64) -> String {
+    if d.is_nan() {
+        "nan".to_string()
+    } else {
+        d.to_string()
     }
 }
 
@@ -162,6 +387,19 @@ mod tests {
         assert_eq!(value, Value::BulkString(Bytes::from("hello")));
     }
 
+    #[test]
+    fn test_parse_bulk_string_shares_the_read_buffer() {
+        let mut buf = BytesMut::from("*2\r\n$4\r\na\r\nb\r\n$1\r\nc\r\n+next\r\n");
+        let payload = buf.as_ptr() as usize + 8;
+        let Value::Array(args) = parse_value(&mut buf).unwrap() else {
+            panic!("expected an array");
+        };
+        assert_eq!(args[0], Value::BulkString(Bytes::from("a\r\nb")));
+        let Value::BulkString(data) = &args[0] else { unreachable!() };
+        assert_eq!(data.as_ptr() as usize, payload);
+        assert_eq!(&buf[..], b"+next\r\n");
+    }
+
     #[test]
     fn test_parse_null_bulk_string() {
         let mut buf = BytesMut::from("$-1\r\n");
@@ -186,6 +424,107 @@ mod tests {
         assert_eq!(value, Value::Null);
     }
 
+    #[test]
+    fn test_parse_double() {
+        let mut buf = BytesMut::from(",3.25\r\n");
+        assert_eq!(parse_value(&mut buf).unwrap(), Value::Double(3.25));
+
+        let mut buf = BytesMut::from(",-inf\r\n");
+        assert_eq!(parse_value(&mut buf).unwrap(), Value::Double(f64::NEG_INFINITY));
+
+        let mut buf = BytesMut::from(",abc\r\n");
+        assert!(matches!(parse_value(&mut buf), Err(ParseError::InvalidFormat)));
+    }
+
+    #[test]
+    fn test_parse_big_number() {
+        let mut buf = BytesMut::from("(3492890328409238509324850943850943825024385\r\n");
+        assert_eq!(
+            parse_value(&mut buf).unwrap(),
+            Value::BigNumber("3492890328409238509324850943850943825024385".to_string())
+        );
+
+        let mut buf = BytesMut::from("(12a\r\n");
+        assert!(matches!(parse_value(&mut buf), Err(ParseError::InvalidFormat)));
+    }
+
+    #[test]
+    fn test_parse_one_byte_at_a_time() {
+        let input = b"*3\r\n$3\r\nSET\r\n$3\r\nkey\r\n$5\r\nvalue\r\n";
+        let mut buf = BytesMut::new();
+        for (i, byte) in input.iter().enumerate() {
+            buf.extend_from_slice(&[*byte]);
+            if i + 1 < input.len() {
+                assert!(matches!(parse_value(&mut buf), Err(ParseError::Incomplete)));
+                assert_eq!(&buf[..], &input[..=i]);
+            }
+        }
+        assert_eq!(parse_value(&mut buf).unwrap(), Value::Array(vec![
+            Value::BulkString(Bytes::from("SET")),
+            Value::BulkString(Bytes::from("key")),
+            Value::BulkString(Bytes::from("value")),
+        ]));
+        assert!(buf.is_empty());
+
+        let mut buf = BytesMut::from("GET ke");
+        assert!(matches!(parse_command(&mut buf, false), Err(ParseError::Incomplete)));
+        buf.extend_from_slice(b"y\r\n");
+        assert_eq!(parse_command(&mut buf, false).unwrap(), Value::Array(vec![
+            Value::BulkString(Bytes::from("GET")),
+            Value::BulkString(Bytes::from("key")),
+        ]));
+    }
+
+    #[test]
+    fn test_parse_empty_commands() {
+        let mut buf = BytesMut::from("*0\r\n");
+        assert_eq!(parse_command(&mut buf, false).unwrap(), Value::Array(vec![]));
+
+        let mut buf = BytesMut::from("\r\n");
+        assert_eq!(parse_command(&mut buf, false).unwrap(), Value::Array(vec![]));
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn test_parse_inline_command() {
+        let mut buf = BytesMut::from("SET key  value\r\n");
+        assert_eq!(parse_command(&mut buf, false).unwrap(), Value::Array(vec![
+            Value::BulkString(Bytes::from("SET")),
+            Value::BulkString(Bytes::from("key")),
+            Value::BulkString(Bytes::from("value")),
+        ]));
+    }
+
+    #[test]
+    fn test_split_inline_args() {
+        let split = |line: &str| split_inline_args(line.as_bytes()).unwrap();
+        let args = |expected: &[&[u8]]| expected.iter().map(|a| Bytes::copy_from_slice(a)).collect::<Vec<_>>();
+
+        assert_eq!(split("set k \"a b\""), args(&[b"set", b"k", b"a b"]));
+        assert_eq!(split("set k 'a\\tb'"), args(&[b"set", b"k", b"a\\tb"]));
+        assert_eq!(split("set k \"a\\tb\""), args(&[b"set", b"k", b"a\tb"]));
+        assert_eq!(split("set k \"\\x41\""), args(&[b"set", b"k", b"A"]));
+        assert_eq!(split("set k 'it\\'s'"), args(&[b"set", b"k", b"it's"]));
+        assert_eq!(split("set k \"\""), args(&[b"set", b"k", b""]));
+        assert_eq!(split("  get   k  "), args(&[b"get", b"k"]));
+        assert_eq!(split(""), args(&[]));
+
+        for bad in ["set k \"a b", "set k 'a", "set k \"a\"b"] {
+            assert!(matches!(split_inline_args(bad.as_bytes()), Err(ParseError::InvalidFormat)), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn test_parse_inline_bare_newline() {
+        let mut buf = BytesMut::from("PING\n");
+        assert_eq!(parse_command(&mut buf, true).unwrap(), Value::Array(vec![
+            Value::BulkString(Bytes::from("PING")),
+        ]));
+
+        let mut buf = BytesMut::from("PING\n");
+        assert!(matches!(parse_command(&mut buf, false), Err(ParseError::InvalidFormat)));
+    }
+
     #[test]
     fn test_serialize_simple_string() {
         let value = Value::SimpleString("OK".to_string());
@@ -206,4 +545,36 @@ mod tests {
         let serialized = serialize_value(&value);
         assert_eq!(serialized, b"$-1\r\n");
     }
+
+    #[test]
+    fn test_serialize_round_trip() {
+        let values = vec![
+            Value::SimpleString("OK".to_string()),
+            Value::Error("ERR something".to_string()),
+            Value::Integer(-42),
+            Value::BulkString(Bytes::from("hello\r\nworld")),
+            Value::BulkString(Bytes::new()),
+            Value::Null,
+            Value::Double(1.5),
+            Value::BigNumber("-12345678901234567890".to_string()),
+            Value::Array(vec![]),
+            Value::Array(vec![
+                Value::Integer(1),
+                Value::Null,
+                Value::Array(vec![Value::BulkString(Bytes::from("nested")), Value::Array(vec![])]),
+            ]),
+        ];
+        for value in values {
+            let mut buf = BytesMut::from(&serialize_value(&value)[..]);
+            assert_eq!(parse_value(&mut buf).unwrap(), value);
+            assert!(buf.is_empty());
+        }
+    }
+
+    #[test]
+    fn test_null_array_is_written_as_null_bulk_string() {
+        let mut buf = BytesMut::from("*-1\r\n");
+        let value = parse_value(&mut buf).unwrap();
+        assert_eq!(serialize_value(&value), b"$-1\r\n");
+    }
 }
\ No newline at end of file